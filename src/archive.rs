@@ -0,0 +1,219 @@
+use crate::error::QgaError;
+use crate::qga::QemuGuestAgent;
+use crate::transfer::{FileJob, FolderReport, TransferOptions};
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Folders with at least this many files go through an archive in `Auto` mode
+pub(crate) const AUTO_ARCHIVE_MIN_FILES: usize = 64;
+
+/// Name of the archive inside the guest folder while an archive upload unpacks
+const UPLOAD_ARCHIVE_NAME: &str = ".qga-upload.tar";
+
+/// Whether folder transfers pack the folder into one tar and unpack it on the
+/// other side instead of sending files one by one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArchiveMode {
+    /// Archive folders with many files, falling back to per-file transfer if
+    /// the guest cannot pack or unpack (but not if it timed out)
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ArchiveMode {
+    pub fn wants(&self, files: usize) -> bool {
+        match self {
+            Self::Auto => files >= AUTO_ARCHIVE_MIN_FILES,
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+impl FromStr for ArchiveMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            other => anyhow::bail!("Unknown archive mode '{}' (expected auto, always or never)", other),
+        }
+    }
+}
+
+impl QemuGuestAgent {
+    /// Packs the walked `dirs` and `files` of a folder into a tar, uploads it into
+    /// `guest_folder` as a single file and unpacks it there with `tar` (`tar.exe`
+    /// on Windows)
+    pub(crate) fn upload_folder_archive(
+        &self,
+        dirs: &[FileJob],
+        files: &[FileJob],
+        guest_folder: &str,
+        options: &TransferOptions,
+    ) -> Result<FolderReport> {
+        // Packing a large folder is wasted work for a guest that cannot unpack it
+        self.run_guest_tar(guest_folder, &["--version"])?;
+
+        let dir = tempfile::tempdir().context("Failed to create a temporary directory")?;
+        let local_archive = dir.path().join(archive_name(Path::new(guest_folder)));
+
+        let mut builder = tar::Builder::new(fs::File::create(&local_archive)?);
+        builder.follow_symlinks(false);
+        for entry in dirs.iter().chain(files) {
+            builder
+                .append_path_with_name(&entry.local, &entry.rel)
+                .with_context(|| format!("Failed to pack {}", entry.local.display()))?;
+        }
+        builder.finish()?;
+
+        self.create_directory(guest_folder)?;
+        let guest_archive = Self::join_guest_path(guest_folder, UPLOAD_ARCHIVE_NAME);
+
+        let result = self
+            .upload_file_with(&local_archive, &guest_archive, options)
+            .and_then(|_| self.run_guest_tar(guest_folder, &["-xf", &guest_archive, "-C", guest_folder]));
+        let _ = self.remove_file(&guest_archive);
+        result?;
+
+        Ok(FolderReport {
+            files: files.len(),
+            ..FolderReport::default()
+        })
+    }
+
+    /// Packs `guest_folder` with `tar` in the guest, downloads the archive and
+    /// unpacks it into `local_folder`
+    pub(crate) fn download_folder_archive(
+        &self,
+        guest_folder: &str,
+        local_folder: &Path,
+        options: &TransferOptions,
+    ) -> Result<FolderReport> {
+        // Outside the folder being packed, which may be a whole drive
+        let guest_archive = self.guest_temp_file(guest_folder)?;
+        let dir = tempfile::tempdir().context("Failed to create a temporary directory")?;
+        let local_archive = dir.path().join(archive_name(Path::new(guest_folder)));
+
+        let result = self
+            .run_guest_tar(guest_folder, &["-cf", &guest_archive, "-C", guest_folder, "."])
+            .and_then(|_| self.download_file_with(&guest_archive, &local_archive, options));
+        let _ = self.remove_file(&guest_archive);
+        result?;
+
+        fs::create_dir_all(local_folder)?;
+        let mut archive = tar::Archive::new(fs::File::open(&local_archive)?);
+        let mut report = FolderReport::default();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let is_file = entry.header().entry_type().is_file();
+            entry
+                .unpack_in(local_folder)
+                .with_context(|| format!("Failed to unpack into {}", local_folder.display()))?;
+            if is_file {
+                report.files += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Whether a failed archive transfer may be retried file by file: only in
+    /// `Auto` mode, and not after a cancel or a timeout, since the guest's tar
+    /// may still be running then
+    pub(crate) fn archive_falls_back(&self, mode: ArchiveMode, err: &anyhow::Error) -> bool {
+        mode == ArchiveMode::Auto
+            && !self.cancel_token().is_cancelled()
+            && !matches!(QgaError::find_in(err), Some(QgaError::Timeout { .. }))
+    }
+
+    /// Runs the guest's `tar` (`tar.exe` on Windows guests) for a transfer of
+    /// `guest_folder`, bounded by `timeouts.archive`
+    fn run_guest_tar(&self, guest_folder: &str, args: &[&str]) -> Result<()> {
+        let tar = if Self::is_windows_path(guest_folder) { "tar.exe" } else { "tar" };
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+
+        let output = self.exec_output_with_timeout(tar, &args, self.timeouts.archive)?;
+        if !output.success() {
+            anyhow::bail!(
+                "{} {} failed in the guest: {}",
+                tar,
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    /// Creates an empty file in the temporary folder of the guest that holds
+    /// `guest_folder` and returns its path
+    fn guest_temp_file(&self, guest_folder: &str) -> Result<String> {
+        let output = if Self::is_windows_path(guest_folder) {
+            self.powershell("[IO.Path]::GetTempFileName()".to_string())?
+        } else {
+            self.exec_output("mktemp", &["-t".to_string(), "qga-download.XXXXXX".to_string()])?
+        };
+
+        let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.success() || path.is_empty() {
+            anyhow::bail!(
+                "Failed to create a temporary file in the guest: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(path)
+    }
+}
+
+/// `<folder name>.tar`, so the progress display names the folder being packed
+fn archive_name(folder: &Path) -> String {
+    let name = folder
+        .to_string_lossy()
+        .trim_end_matches(['/', '\\'])
+        .rsplit(['/', '\\'])
+        .next()
+        .filter(|n| !n.is_empty())
+        .unwrap_or("folder")
+        .to_string();
+    format!("{}.tar", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_guest::HostGuest;
+
+    #[test]
+    fn folder_round_trip_through_archives() {
+        let (agent, guest) = HostGuest::agent();
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("local");
+        fs::create_dir_all(local.join("sub")).unwrap();
+        fs::write(local.join("a.txt"), "a").unwrap();
+        fs::write(local.join("sub/b.txt"), "b").unwrap();
+        let guest_folder = dir.path().join("guest").to_str().unwrap().to_string();
+        let options = TransferOptions { archive: ArchiveMode::Always, ..TransferOptions::default() };
+
+        let report = agent.upload_folder_with(&local, &guest_folder, &options).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(fs::read_to_string(Path::new(&guest_folder).join("sub/b.txt")).unwrap(), "b");
+        assert!(!Path::new(&guest_folder).join(UPLOAD_ARCHIVE_NAME).exists());
+
+        let downloaded = dir.path().join("downloaded");
+        let report = agent.download_folder_with(&guest_folder, &downloaded, &options).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(fs::read_to_string(downloaded.join("sub/b.txt")).unwrap(), "b");
+
+        // The guest packed into its temporary folder, not beside or inside the folder
+        let guest = guest.lock().unwrap();
+        let pack = guest.exec_log.iter().find(|c| c[0] == "tar" && c[1] == "-cf").unwrap();
+        assert!(!pack[2].starts_with(dir.path().to_str().unwrap()), "packed into {}", pack[2]);
+        assert!(!Path::new(&pack[2]).exists(), "the guest archive is removed");
+    }
+}
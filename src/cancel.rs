@@ -0,0 +1,27 @@
+use crate::error::{QgaError, QgaResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared flag that asks long-running operations to stop. Clones observe the
+/// same flag, so one can be handed to a signal handler while the agent keeps
+/// another; operations notice it between chunks, files and exec polls.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fails with [`QgaError::Cancelled`] once cancelled
+    pub fn check(&self) -> QgaResult<()> {
+        if self.is_cancelled() {
+            return Err(QgaError::Cancelled);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use crate::error::{QgaError, QgaResult};
+use crate::qga::{GuestInfo, QemuGuestAgent};
+use std::collections::HashMap;
+
+/// Which agent commands the guest offers, as reported by `guest-info`
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// Command name to enabled flag; `None` when `guest-info` was unavailable
+    commands: Option<HashMap<String, bool>>,
+}
+
+impl Capabilities {
+    pub fn from_info(info: &GuestInfo) -> Self {
+        Self {
+            commands: Some(
+                info.supported_commands
+                    .iter()
+                    .map(|c| (c.name.clone(), c.enabled))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Capabilities of an agent that could not be queried. Every command is
+    /// assumed available and failures surface when it is actually used.
+    pub fn unknown() -> Self {
+        Self { commands: None }
+    }
+
+    pub fn supports(&self, command: &str) -> bool {
+        self.check(command).is_ok()
+    }
+
+    pub fn check(&self, command: &str) -> QgaResult<()> {
+        let Some(commands) = &self.commands else {
+            return Ok(());
+        };
+
+        match commands.get(command) {
+            Some(true) => Ok(()),
+            Some(false) => Err(QgaError::CommandDisabled {
+                command: command.to_string(),
+            }),
+            None => Err(QgaError::CommandUnsupported {
+                command: command.to_string(),
+            }),
+        }
+    }
+}
+
+impl QemuGuestAgent {
+    /// Queries `guest-info` on first use and caches the result for the session
+    pub fn capabilities(&self) -> &Capabilities {
+        self.capabilities.get_or_init(|| {
+            self.guest_info()
+                .map(|info| Capabilities::from_info(&info))
+                .unwrap_or_else(|_| Capabilities::unknown())
+        })
+    }
+
+    pub fn supports(&self, command: &str) -> bool {
+        self.capabilities().supports(command)
+    }
+
+    /// Fails with the first of `commands` the guest agent cannot run
+    pub fn require_commands(&self, commands: &[&str]) -> QgaResult<()> {
+        let capabilities = self.capabilities();
+        commands.iter().try_for_each(|c| capabilities.check(c))
+    }
+}
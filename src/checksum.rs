@@ -0,0 +1,112 @@
+use crate::qga::QemuGuestAgent;
+use crate::transfer::FILE_READ_COMMANDS;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Buffer size when hashing a file by reading it back through `guest-file-read`
+const READ_BACK_CHUNK: usize = 4 * 1024 * 1024;
+
+/// The guest copy of a transferred file does not hash to the same SHA-256 as the local one
+#[derive(Debug, Clone)]
+pub struct ChecksumMismatch {
+    pub local_path: PathBuf,
+    pub guest_path: String,
+    pub local: String,
+    pub guest: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checksum mismatch: {} is {} but {} is {}",
+            self.local_path.display(),
+            self.local,
+            self.guest_path,
+            self.guest
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Lowercase hex SHA-256 of everything `reader` yields
+pub(crate) fn sha256_reader(mut reader: impl Read, chunk: usize) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; chunk];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+pub(crate) fn sha256_file(path: &Path) -> Result<String> {
+    let file = fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    sha256_reader(file, 64 * 1024).with_context(|| format!("Failed to hash {}", path.display()))
+}
+
+impl QemuGuestAgent {
+    /// SHA-256 of a guest file, computed in the guest with `Get-FileHash` or
+    /// `sha256sum` when exec is available and by reading the file back otherwise
+    pub fn guest_sha256(&self, path: &str) -> Result<String> {
+        if self.can_exec() {
+            if let Some(hash) = self.guest_sha256_exec(path)? {
+                return Ok(hash);
+            }
+        }
+
+        self.require_commands(FILE_READ_COMMANDS)?;
+        let normalized = Self::guest_file_path(path);
+        let file = self.open_file(&normalized, "r")?;
+        sha256_reader(file, READ_BACK_CHUNK).with_context(|| format!("Failed to read back {}", path))
+    }
+
+    /// Hashes with the guest's own tools; `None` when they are missing or fail
+    fn guest_sha256_exec(&self, path: &str) -> Result<Option<String>> {
+        let output = if Self::is_windows_path(path) {
+            let ps_cmd = format!(
+                "(Get-FileHash -Algorithm SHA256 -LiteralPath '{}').Hash",
+                path.replace('\'', "''")
+            );
+            self.powershell(ps_cmd)?
+        } else {
+            self.exec_output("sha256sum", &["--".to_string(), path.to_string()])?
+        };
+
+        if !output.success() {
+            return Ok(None);
+        }
+
+        let hash = String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .next()
+            .map(str::to_ascii_lowercase);
+        Ok(hash.filter(|h| h.len() == 64 && h.bytes().all(|b| b.is_ascii_hexdigit())))
+    }
+
+    /// Fails with [`ChecksumMismatch`] unless both copies hash the same
+    pub fn verify_file(&self, local_path: &Path, guest_path: &str) -> Result<()> {
+        let local = sha256_file(local_path)?;
+        let guest = self.guest_sha256(guest_path)?;
+
+        if local != guest {
+            return Err(ChecksumMismatch {
+                local_path: local_path.to_path_buf(),
+                guest_path: guest_path.to_string(),
+                local,
+                guest,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
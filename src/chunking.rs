@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+/// Default chunk size, kept small enough for any transport
+pub(crate) const DEFAULT_CHUNK_SIZE: usize = 4096;
+/// Largest chunk used for transfers. `guest-file-read` allows 48MB, but the
+/// base64 encoding of a write that size would exceed the agent's JSON token limit.
+pub const MAX_CHUNK_SIZE: usize = 32 * 1024 * 1024;
+
+/// How many bytes each `guest-file-read`/`guest-file-write` round trip moves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkSize {
+    Fixed(usize),
+    /// Start at `min` and double while round trips stay under `target`, halving when they exceed it
+    Adaptive { min: usize, max: usize, target: Duration },
+}
+
+impl Default for ChunkSize {
+    fn default() -> Self {
+        Self::Fixed(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl ChunkSize {
+    pub fn adaptive() -> Self {
+        Self::Adaptive {
+            min: 64 * 1024,
+            max: MAX_CHUNK_SIZE,
+            target: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Tracks the chunk size for one transfer, growing or shrinking it in adaptive mode
+#[derive(Debug, Clone)]
+pub(crate) struct Chunker {
+    mode: ChunkSize,
+    size: usize,
+    limit: usize,
+}
+
+impl Chunker {
+    /// `limit` caps the size regardless of mode, e.g. for transports with a command length limit
+    pub fn new(mode: ChunkSize, limit: usize) -> Self {
+        let size = match mode {
+            ChunkSize::Fixed(size) => size,
+            ChunkSize::Adaptive { min, .. } => min,
+        };
+        Self {
+            mode,
+            size: size.clamp(1, limit.max(1)),
+            limit: limit.max(1),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Feeds back how long the last chunk's round trip took
+    pub fn record(&mut self, elapsed: Duration) {
+        let ChunkSize::Adaptive { min, max, target } = self.mode else {
+            return;
+        };
+        let max = max.min(self.limit);
+
+        if elapsed < target / 2 {
+            self.size = (self.size * 2).min(max);
+        } else if elapsed > target {
+            self.size = (self.size / 2).max(min.min(max));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_size_never_changes() {
+        let mut chunker = Chunker::new(ChunkSize::Fixed(8192), MAX_CHUNK_SIZE);
+        chunker.record(Duration::from_millis(1));
+        chunker.record(Duration::from_secs(10));
+        assert_eq!(chunker.size(), 8192);
+    }
+
+    #[test]
+    fn limit_caps_the_size() {
+        assert_eq!(Chunker::new(ChunkSize::Fixed(1 << 20), 4096).size(), 4096);
+        assert_eq!(Chunker::new(ChunkSize::Fixed(1 << 20), 0).size(), 1);
+    }
+
+    #[test]
+    fn adaptive_grows_while_fast_and_shrinks_when_slow() {
+        let mode = ChunkSize::Adaptive {
+            min: 1024,
+            max: 8192,
+            target: Duration::from_millis(100),
+        };
+        let mut chunker = Chunker::new(mode, MAX_CHUNK_SIZE);
+        assert_eq!(chunker.size(), 1024);
+
+        for expected in [2048, 4096, 8192, 8192] {
+            chunker.record(Duration::from_millis(10));
+            assert_eq!(chunker.size(), expected);
+        }
+        // Between half the target and the target the size holds
+        chunker.record(Duration::from_millis(80));
+        assert_eq!(chunker.size(), 8192);
+
+        for expected in [4096, 2048, 1024, 1024] {
+            chunker.record(Duration::from_millis(500));
+            assert_eq!(chunker.size(), expected);
+        }
+    }
+
+    #[test]
+    fn adaptive_respects_the_transport_limit() {
+        let mut chunker = Chunker::new(ChunkSize::adaptive(), 100 * 1024);
+        for _ in 0..10 {
+            chunker.record(Duration::ZERO);
+        }
+        assert_eq!(chunker.size(), 100 * 1024);
+    }
+}
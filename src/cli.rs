@@ -1,5 +1,9 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use qemu_agent::{ArchiveMode, ChunkSize, Preserve, Privilege, TransferOptions, MAX_CHUNK_SIZE};
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "qemu-agent")]
@@ -8,6 +12,46 @@ pub struct Cli {
     /// VM name
     pub vm_name: String,
 
+    /// Talk to the guest agent socket directly instead of going through virsh
+    #[arg(long, global = true, value_name = "PATH")]
+    pub socket: Option<PathBuf>,
+
+    /// Talk to a guest agent chardev exposed over TCP (HOST:PORT)
+    #[arg(long, global = true, value_name = "ADDR", conflicts_with = "socket")]
+    pub tcp: Option<String>,
+
+    /// libvirt connection URI for virsh (e.g. qemu:///session, qemu+ssh://host/system)
+    #[arg(short = 'c', long, global = true, value_name = "URI")]
+    pub connect: Option<String>,
+
+    /// How to elevate virsh: none, sudo or pkexec
+    #[arg(long, global = true, value_name = "MODE", default_value = "sudo")]
+    pub privilege: Privilege,
+
+    /// virsh binary to run
+    #[arg(long, global = true, value_name = "PATH", default_value = "virsh")]
+    pub virsh: PathBuf,
+
+    /// Timeout for each guest agent command (e.g. 30s, 2m)
+    #[arg(long, global = true, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+    pub agent_timeout: Duration,
+
+    /// How long guest processes started by an operation may run
+    #[arg(long, global = true, value_name = "DURATION", default_value = "60s", value_parser = parse_duration)]
+    pub exec_timeout: Duration,
+
+    /// How long the guest's tar may take to pack or unpack a folder in archive mode
+    #[arg(long, global = true, value_name = "DURATION", default_value = "30m", value_parser = parse_duration)]
+    pub archive_timeout: Duration,
+
+    /// Bytes per file read/write round trip (e.g. 64K, 1M), or "auto" to grow it while round trips stay fast
+    #[arg(long, global = true, value_name = "SIZE", default_value = "4K", value_parser = parse_chunk_size)]
+    pub chunk_size: ChunkSize,
+
+    /// Skip the guest-ping check before running the command
+    #[arg(long, global = true)]
+    pub no_ping: bool,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -20,6 +64,8 @@ pub enum Commands {
         source: PathBuf,
         /// Destination path on guest
         dest: String,
+        #[command(flatten)]
+        transfer: TransferArgs,
     },
     /// Download file or folder from guest
     Download {
@@ -27,6 +73,26 @@ pub enum Commands {
         source: String,
         /// Destination path on host
         dest: PathBuf,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Transfer only files that changed between a host folder and a guest folder
+    Sync {
+        /// Folder on host
+        local: PathBuf,
+        /// Folder on guest
+        guest: String,
+        /// Copy from the guest to the host instead
+        #[arg(long)]
+        pull: bool,
+        /// Compare SHA-256 of same-sized files instead of modification times
+        #[arg(long)]
+        checksum: bool,
+        /// Remove destination files that no longer exist at the source
+        #[arg(long)]
+        delete: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
     },
     /// Paste clipboard content to guest (auto-detects files/text and destination)
     Paste {
@@ -44,4 +110,208 @@ pub enum Commands {
         /// Arguments
         args: Vec<String>,
     },
+    /// Send an arbitrary guest agent command and print its return value
+    Raw {
+        /// Command name, e.g. guest-info
+        #[arg(required_unless_present = "file")]
+        command: Option<String>,
+        /// Command arguments as a JSON object
+        args: Option<String>,
+        /// Read newline-delimited commands from a file ("-" for stdin)
+        #[arg(short, long, value_name = "PATH", conflicts_with = "command")]
+        file: Option<PathBuf>,
+    },
+    /// Block until the guest agent answers (exits 124 on timeout)
+    Wait {
+        /// How long to keep polling
+        #[arg(long, value_name = "DURATION", default_value = "300s", value_parser = parse_duration)]
+        timeout: Duration,
+        /// Also query guest-info and report the agent version
+        #[arg(long)]
+        info: bool,
+        /// Require this agent command to be enabled (repeatable)
+        #[arg(long = "require", value_name = "COMMAND")]
+        require: Vec<String>,
+    },
+}
+
+/// Options shared by upload and download
+#[derive(Args)]
+pub struct TransferArgs {
+    /// Continue interrupted transfers from the size already at the destination
+    #[arg(long)]
+    pub resume: bool,
+    /// With --resume, compare this many bytes before the resume point (e.g. 64K) and restart on mismatch
+    #[arg(long, value_name = "SIZE", requires = "resume", value_parser = parse_size)]
+    pub verify_tail: Option<u64>,
+    /// Compare SHA-256 of every file on host and guest after transferring it
+    #[arg(long)]
+    pub verify: bool,
+    /// Upload straight to the destination instead of a temporary name renamed into place
+    #[arg(long)]
+    pub no_atomic: bool,
+    /// Keep partially transferred files when interrupted with Ctrl+C
+    #[arg(long)]
+    pub keep_partial: bool,
+    /// Transfer up to N files of a folder at once
+    #[arg(short, long, value_name = "N", default_value_t = 1)]
+    pub jobs: usize,
+    /// Send folders as one tar unpacked on the other side: auto (64+ files), always or never
+    #[arg(long, value_name = "MODE", default_value = "auto")]
+    pub archive: ArchiveMode,
+    /// Update existing guest files rsync-style, sending only data the guest copy lacks, then verify the whole file
+    #[arg(long)]
+    pub delta: bool,
+    /// Include hidden files and folders in folder transfers (the default)
+    #[arg(long, overrides_with = "no_hidden")]
+    pub hidden: bool,
+    /// Leave out dotfiles and, on Windows guests, entries with the hidden attribute
+    #[arg(long, overrides_with = "hidden")]
+    pub no_hidden: bool,
+    /// Carry metadata over: comma-separated times, mode, attrs, owner, or all (alone: times,mode,attrs)
+    #[arg(long, value_name = "LIST", num_args = 0..=1, default_missing_value = "times,mode,attrs")]
+    pub preserve: Option<Preserve>,
+    /// Only transfer files of a folder matching PATTERN (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub include: Vec<String>,
+    /// Leave out files and folders matching PATTERN (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+    /// Read exclude patterns from FILE, one per line
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Vec<PathBuf>,
+    /// Leave out what .gitignore and .ignore files in the folder ignore
+    #[arg(long)]
+    pub gitignore: bool,
+}
+
+impl TransferArgs {
+    pub fn options(&self) -> Result<TransferOptions> {
+        let mut exclude = self.exclude.clone();
+        for path in &self.exclude_from {
+            exclude.extend(read_pattern_file(path)?);
+        }
+
+        Ok(TransferOptions {
+            resume: self.resume,
+            verify_tail: self.verify_tail.unwrap_or(0),
+            verify: self.verify,
+            atomic: !self.no_atomic,
+            keep_partial: self.keep_partial,
+            jobs: self.jobs.max(1),
+            archive: self.archive,
+            delta: self.delta,
+            include_hidden: !self.no_hidden,
+            preserve: self.preserve.unwrap_or_default(),
+            include: self.include.clone(),
+            exclude,
+            ignore_files: self.gitignore,
+        })
+    }
+}
+
+/// Parses durations such as `500ms`, `30s`, `5m`, `1h`; a bare number means seconds
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+
+    if number == 0 {
+        return Err(format!("duration '{}' must be greater than zero", value));
+    }
+
+    let too_large = || format!("duration '{}' is too large", value);
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => number.checked_mul(60).map(Duration::from_secs).ok_or_else(too_large),
+        "h" => number.checked_mul(3600).map(Duration::from_secs).ok_or_else(too_large),
+        _ => Err(format!("invalid duration unit '{}' (expected ms, s, m or h)", unit)),
+    }
+}
+
+/// Parses a byte count such as `4096`, `64K`, `1M` or `2G`
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size unit '{}' (expected K, M or G)", unit)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", value))
+}
+
+/// Accepts `auto` or a size such as `4096`, `64K`, `1M`
+pub fn parse_chunk_size(value: &str) -> Result<ChunkSize, String> {
+    if value.eq_ignore_ascii_case("auto") {
+        return Ok(ChunkSize::adaptive());
+    }
+
+    match parse_size(value)? {
+        size if (1..=MAX_CHUNK_SIZE as u64).contains(&size) => Ok(ChunkSize::Fixed(size as usize)),
+        _ => Err(format!("chunk size must be between 1 byte and {}M", MAX_CHUNK_SIZE / (1024 * 1024))),
+    }
+}
+
+/// Patterns from an `--exclude-from` style file: one per line, skipping blank
+/// lines and `#` comments
+pub fn read_pattern_file(path: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("1m"), Ok(1024 * 1024));
+        assert_eq!(parse_size("2GiB"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("12T").is_err());
+        assert!(parse_size("99999999999999999999G").is_err());
+        assert!(parse_size("18446744073709551615G").is_err());
+    }
+
+    #[test]
+    fn parses_chunk_sizes() {
+        assert_eq!(parse_chunk_size("4K"), Ok(ChunkSize::Fixed(4096)));
+        assert_eq!(parse_chunk_size("AUTO"), Ok(ChunkSize::adaptive()));
+        assert!(parse_chunk_size("0").is_err());
+        assert!(parse_chunk_size("64M").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("18446744073709551615h").is_err());
+    }
 }
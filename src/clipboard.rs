@@ -1,13 +1,23 @@
-use crate::colors::Tags;
 use crate::qga::QemuGuestAgent;
+use crate::transfer::FolderReport;
 use anyhow::Result;
 use arboard::Clipboard;
-use base64::{engine::general_purpose, Engine as _};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+/// One clipboard entry handled by [`QemuGuestAgent::paste_clipboard`]
+#[derive(Debug)]
+pub enum PastedItem {
+    File { source: PathBuf, guest_path: String },
+    Folder { source: PathBuf, guest_path: String, report: FolderReport },
+    Text { bytes: usize, guest_path: String },
+    /// A path listed in the clipboard that does not exist locally
+    Missing { path: String },
+}
+
 impl QemuGuestAgent {
-    pub fn paste_clipboard(&self, source: Option<PathBuf>, dest: String) -> Result<()> {
+    pub fn paste_clipboard(&self, source: Option<PathBuf>, dest: String) -> Result<Vec<PastedItem>> {
         let dest_path = dest;
 
         if let Some(source_path) = source {
@@ -22,23 +32,21 @@ impl QemuGuestAgent {
                 format!("{}/{}", dest_path, filename)
             };
 
-            if source_path.is_file() {
-                println!("{} Uploading file: {} -> {}", Tags::upload(), source_path.display(), guest_dest);
+            let item = if source_path.is_file() {
                 self.upload_file(&source_path, &guest_dest)?;
-                println!("{} Upload complete", Tags::ok());
+                PastedItem::File { source: source_path, guest_path: guest_dest }
             } else if source_path.is_dir() {
-                self.upload_folder(&source_path, &guest_dest)?;
+                let report = self.upload_folder(&source_path, &guest_dest)?;
+                PastedItem::Folder { source: source_path, guest_path: guest_dest, report }
             } else {
                 anyhow::bail!("Source path does not exist: {}", source_path.display());
-            }
+            };
 
-            return Ok(());
+            return Ok(vec![item]);
         }
 
-        println!("{} Reading clipboard...", Tags::clip());
-        
         let mut content = Command::new("wl-paste")
-            .args(&["--type", "x-special/gnome-copied-files"])
+            .args(["--type", "x-special/gnome-copied-files"])
             .output()
             .ok()
             .and_then(|o| {
@@ -51,7 +59,7 @@ impl QemuGuestAgent {
 
         if content.is_none() {
             content = Command::new("wl-paste")
-                .args(&["--type", "text/uri-list"])
+                .args(["--type", "text/uri-list"])
                 .output()
                 .ok()
                 .and_then(|o| {
@@ -74,8 +82,6 @@ impl QemuGuestAgent {
             anyhow::anyhow!("Clipboard is empty or contains no file/text data")
         })?;
 
-        println!("{} Clipboard content detected ({} bytes)", Tags::clip(), content.len());
-
         let lines: Vec<&str> = content.lines().collect();
         
         let is_file_list = lines.iter().any(|line| {
@@ -84,9 +90,9 @@ impl QemuGuestAgent {
             (line.len() > 2 && line.chars().nth(1) == Some(':'))
         });
 
+        let mut items = Vec::new();
+
         if is_file_list {
-            println!("{} Detected file/folder paths in clipboard", Tags::clip());
-            
             for line in lines {
                 let line = line.trim();
                 if line.is_empty() || line == "copy" || line == "cut" {
@@ -107,7 +113,7 @@ impl QemuGuestAgent {
                 let source_path = Path::new(&path);
                 
                 if !source_path.exists() {
-                    println!("{} Non-existent path: {}", Tags::skip(), path);
+                    items.push(PastedItem::Missing { path });
                     continue;
                 }
 
@@ -123,15 +129,13 @@ impl QemuGuestAgent {
                 };
 
                 if source_path.is_file() {
-                    println!("{} Uploading file: {} -> {}", Tags::file(), path, guest_dest);
                     self.upload_file(source_path, &guest_dest)?;
+                    items.push(PastedItem::File { source: source_path.to_path_buf(), guest_path: guest_dest });
                 } else if source_path.is_dir() {
-                    println!("{} Uploading folder: {} -> {}", Tags::folder(), path, guest_dest);
-                    self.upload_folder(source_path, &guest_dest)?;
+                    let report = self.upload_folder(source_path, &guest_dest)?;
+                    items.push(PastedItem::Folder { source: source_path.to_path_buf(), guest_path: guest_dest, report });
                 }
             }
-
-            println!("{} Clipboard files pasted successfully", Tags::ok());
         } else {
             let timestamp = std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)?
@@ -144,27 +148,15 @@ impl QemuGuestAgent {
                 format!("{}/{}", dest_path, filename)
             };
 
-            println!("{} Pasting text to: {}", Tags::text(), full_path);
-
-            let normalized_path = if Self::is_windows_path(&full_path) {
-                Self::normalize_windows_path(&full_path)
-            } else {
-                full_path.clone()
-            };
-
-            let handle = self.guest_file_open(&normalized_path, "w+")?;
-
-            let result = (|| -> Result<()> {
-                self.guest_file_write(handle, content.as_bytes())?;
-                Ok(())
-            })();
+            let normalized_path = Self::guest_file_path(&full_path);
 
-            self.guest_file_close(handle)?;
-            result?;
+            let mut guest_file = self.open_file(&normalized_path, "w+")?;
+            guest_file.write_all(content.as_bytes())?;
+            guest_file.close()?;
 
-            println!("{} Clipboard pasted successfully to {}", Tags::ok(), full_path);
+            items.push(PastedItem::Text { bytes: content.len(), guest_path: full_path });
         }
 
-        Ok(())
+        Ok(items)
     }
 }
@@ -26,10 +26,6 @@ impl Tags {
         format!("{}[SKIP]{}", Colors::BOLD_YELLOW, Colors::RESET)
     }
     
-    pub fn detect() -> String {
-        format!("{}[DETECT]{}", Colors::BOLD_BLUE, Colors::RESET)
-    }
-    
     pub fn interrupt() -> String {
         format!("{}[INTERRUPT]{}", Colors::BOLD_YELLOW, Colors::RESET)
     }
@@ -61,4 +57,16 @@ impl Tags {
     pub fn exec() -> &'static str {
         "[EXEC]"
     }
+
+    pub fn raw() -> &'static str {
+        "[RAW]"
+    }
+
+    pub fn wait() -> &'static str {
+        "[WAIT]"
+    }
+
+    pub fn sync() -> String {
+        format!("{}[SYNC]{}", Colors::BOLD_BLUE, Colors::RESET)
+    }
 }
@@ -0,0 +1,634 @@
+use crate::events::TransferEvent;
+use crate::qga::QemuGuestAgent;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Size of the guest file blocks that delta uploads look for in the local file
+pub(crate) const DELTA_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Generator polynomial of the CRC-32 that POSIX `cksum` computes
+const CKSUM_POLY: u32 = 0x04C1_1DB7;
+
+/// How much of the local file the block matcher reads at a time
+const READ_AHEAD: usize = 4 * DELTA_BLOCK_SIZE;
+
+/// Hashes each block of a Windows guest file the way `split --filter=cksum`
+/// and `split --filter=sha256sum` do on Linux
+const WINDOWS_SIGNATURES: &str = r#"
+public static class QgaDelta {
+    static uint[] table = MakeTable();
+    static uint[] MakeTable() {
+        var t = new uint[256];
+        for (uint i = 0; i < 256; i++) {
+            uint c = i << 24;
+            for (int k = 0; k < 8; k++) c = (c & 0x80000000) != 0 ? (c << 1) ^ 0x04C11DB7 : c << 1;
+            t[i] = c;
+        }
+        return t;
+    }
+    public static string[] Signatures(string path, int size) {
+        var weak = new System.Collections.Generic.List<string>();
+        var strong = new System.Collections.Generic.List<string>();
+        var sha = System.Security.Cryptography.SHA256.Create();
+        var buf = new byte[size];
+        using (var f = System.IO.File.OpenRead(path)) {
+            while (true) {
+                int n = 0;
+                while (n < size) { int r = f.Read(buf, n, size - n); if (r == 0) break; n += r; }
+                if (n == 0) break;
+                uint c = 0;
+                for (int i = 0; i < n; i++) c = (c << 8) ^ table[(c >> 24) ^ buf[i]];
+                for (long len = n; len > 0; len >>= 8) c = (c << 8) ^ table[(c >> 24) ^ (uint)(len & 0xFF)];
+                weak.Add((~c).ToString() + " " + n);
+                strong.Add(System.BitConverter.ToString(sha.ComputeHash(buf, 0, n)).Replace("-", "").ToLower());
+                if (n < size) break;
+            }
+        }
+        weak.Add("--");
+        weak.AddRange(strong);
+        return weak.ToArray();
+    }
+}
+"#;
+
+/// Checksums of one block of the guest file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BlockSignature {
+    /// POSIX `cksum` of the block, cheap to roll along the local file
+    pub weak: u32,
+    /// SHA-256 of the block, hex encoded
+    pub strong: String,
+    pub len: usize,
+}
+
+/// One piece of the file a delta upload builds in the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeltaOp {
+    /// `len` bytes the guest file already holds at `offset`
+    Copy { offset: u64, len: u64 },
+    /// `len` bytes of the local file at `offset`, which have to be sent
+    Literal { offset: u64, len: u64 },
+}
+
+impl QemuGuestAgent {
+    /// Checksums of each `block_size` block of a guest file, in order. Computed
+    /// in the guest with `split --filter` or a compiled PowerShell helper when
+    /// exec is available; otherwise the file is read back and hashed here.
+    pub(crate) fn guest_block_signatures(&self, path: &str, block_size: usize) -> Result<Vec<BlockSignature>> {
+        if !self.can_exec() {
+            let normalized = Self::guest_file_path(path);
+            let file = self.open_file(&normalized, "r")?;
+            return block_signatures(file, block_size).with_context(|| format!("Failed to read back {}", path));
+        }
+
+        let output = if Self::is_windows_path(path) {
+            let script = format!(
+                "$ErrorActionPreference = 'Stop'\nAdd-Type -TypeDefinition @'{}'@\n[QgaDelta]::Signatures('{}', {})",
+                WINDOWS_SIGNATURES,
+                path.replace('\'', "''"),
+                block_size
+            );
+            self.powershell(script)?
+        } else {
+            let script = r#"split -b "$1" --filter=cksum -- "$2" && echo -- && split -b "$1" --filter=sha256sum -- "$2""#;
+            self.exec_output(
+                "sh",
+                &["-c", script, "sh", &block_size.to_string(), path].map(String::from),
+            )?
+        };
+
+        if !output.success() {
+            anyhow::bail!(
+                "Failed to hash the blocks of {}: {}",
+                path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        parse_signatures(&String::from_utf8_lossy(&output.stdout))
+            .with_context(|| format!("Unexpected block checksums for {}", path))
+    }
+
+    /// Brings an existing guest file in line with `local_path`, sending only
+    /// the data the guest does not already have, then verifies the SHA-256 of
+    /// the whole file. With exec, blocks of the guest file are found wherever
+    /// they moved to and the guest rebuilds the file beside the old one;
+    /// without it, changed blocks are rewritten in place at fixed offsets.
+    pub(crate) fn upload_file_delta(&self, local_path: &Path, guest_path: &str) -> Result<()> {
+        let signatures = self.guest_block_signatures(guest_path, DELTA_BLOCK_SIZE)?;
+        let guest_len: u64 = signatures.iter().map(|s| s.len as u64).sum();
+        let local_len = fs::metadata(local_path)?.len();
+
+        let event_path = local_path.display().to_string();
+        self.emit(TransferEvent::FileStarted { path: event_path.clone(), size: Some(local_len) });
+
+        let result = if self.can_exec() {
+            self.rebuild_guest_file(local_path, guest_path, &signatures, &event_path)
+        } else if guest_len > local_len {
+            // Nothing can shorten a guest file without exec, so rewrite it whole
+            self.patch_guest_file(local_path, guest_path, &[], &event_path)
+        } else {
+            self.patch_guest_file(local_path, guest_path, &signatures, &event_path)
+        };
+
+        self.emit_outcome(event_path.clone(), &result);
+        result?;
+
+        self.verify_transfer(event_path, local_path, guest_path)
+    }
+
+    /// Uploads the literal runs of `local_path` and has the guest assemble them
+    /// with the blocks it already has into the staging file, which then
+    /// replaces `guest_path`
+    fn rebuild_guest_file(
+        &self,
+        local_path: &Path,
+        guest_path: &str,
+        signatures: &[BlockSignature],
+        event_path: &str,
+    ) -> Result<()> {
+        let file = fs::File::open(local_path)?;
+        let ops = match_blocks(file, signatures, DELTA_BLOCK_SIZE, || Ok(self.check_cancelled()?))?;
+
+        let staging = Self::staging_path(guest_path);
+        let literals = format!("{}.lit", staging);
+        let plan = format!("{}.ops", staging);
+
+        let result = (|| -> Result<()> {
+            let mut file = fs::File::open(local_path)?;
+            let mut guest_file = self.open_file(&Self::guest_file_path(&literals), "w")?;
+            let mut script = String::new();
+            let mut literal_offset = 0;
+            let mut buffer = vec![0u8; self.write_chunk_limit()];
+
+            for op in &ops {
+                self.check_cancelled()?;
+                match *op {
+                    DeltaOp::Copy { offset, len } => {
+                        script.push_str(&format!("c {} {}\n", offset, len));
+                        self.emit(TransferEvent::BytesTransferred { path: event_path.to_string(), bytes: len });
+                    }
+                    DeltaOp::Literal { offset, len } => {
+                        script.push_str(&format!("l {} {}\n", literal_offset, len));
+                        literal_offset += len;
+
+                        file.seek(SeekFrom::Start(offset))?;
+                        let mut left = len;
+                        while left > 0 {
+                            self.check_cancelled()?;
+                            let n = left.min(buffer.len() as u64) as usize;
+                            file.read_exact(&mut buffer[..n])?;
+                            guest_file.write_all(&buffer[..n])?;
+                            self.emit(TransferEvent::BytesTransferred { path: event_path.to_string(), bytes: n as u64 });
+                            left -= n as u64;
+                        }
+                    }
+                }
+            }
+            guest_file.close()?;
+
+            let mut plan_file = self.open_file(&Self::guest_file_path(&plan), "w")?;
+            plan_file.write_all(script.as_bytes())?;
+            plan_file.close()?;
+
+            self.check_cancelled()?;
+            self.assemble_guest_file(guest_path, &literals, &plan, &staging)
+        })();
+
+        let _ = self.remove_file(&literals);
+        let _ = self.remove_file(&plan);
+        if let Err(err) = result {
+            let _ = self.remove_file(&staging);
+            return Err(err);
+        }
+        self.replace_with_staging(&staging, guest_path)
+    }
+
+    /// Writes `output` from the `c OFFSET LEN` (from `old`) and `l OFFSET LEN`
+    /// (from `literals`) lines of `plan`: GNU `dd` on Linux, a `FileStream`
+    /// loop on Windows
+    fn assemble_guest_file(&self, old: &str, literals: &str, plan: &str, output: &str) -> Result<()> {
+        let result = if Self::is_windows_path(old) {
+            let quote = |path: &str| format!("'{}'", path.replace('\'', "''"));
+            let script = format!(
+                "$ErrorActionPreference = 'Stop'\n\
+                 $old = [IO.File]::OpenRead({old}); $lit = [IO.File]::OpenRead({lit}); $out = [IO.File]::Create({out})\n\
+                 $buf = New-Object byte[] 1048576\n\
+                 foreach ($line in [IO.File]::ReadAllLines({plan})) {{\n\
+                     $kind, $offset, $left = $line.Split(' '); $left = [long]$left\n\
+                     $src = if ($kind -eq 'c') {{ $old }} else {{ $lit }}\n\
+                     [void]$src.Seek([long]$offset, 'Begin')\n\
+                     while ($left -gt 0) {{\n\
+                         $n = $src.Read($buf, 0, [Math]::Min($left, $buf.Length))\n\
+                         if ($n -eq 0) {{ throw 'Unexpected end of file' }}\n\
+                         $out.Write($buf, 0, $n); $left -= $n\n\
+                     }}\n\
+                 }}\n\
+                 $out.Close(); $old.Close(); $lit.Close()",
+                old = quote(old),
+                lit = quote(literals),
+                out = quote(output),
+                plan = quote(plan)
+            );
+            self.powershell(script)?
+        } else {
+            let script = r#"set -e
+while read -r kind offset len; do
+    if [ "$kind" = c ]; then src=$1; else src=$2; fi
+    dd if="$src" bs=1M iflag=skip_bytes,count_bytes skip="$offset" count="$len" status=none
+done < "$3" > "$4""#;
+            self.exec_output(
+                "sh",
+                &["-c", script, "sh", old, literals, plan, output].map(String::from),
+            )?
+        };
+
+        if !result.success() {
+            anyhow::bail!(
+                "Failed to assemble {} in the guest: {}",
+                old,
+                String::from_utf8_lossy(&result.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    /// Rewrites in place the blocks whose hash differs from `signatures`, which
+    /// describe the guest file at the same offsets
+    fn patch_guest_file(
+        &self,
+        local_path: &Path,
+        guest_path: &str,
+        signatures: &[BlockSignature],
+        event_path: &str,
+    ) -> Result<()> {
+        let mode = if signatures.is_empty() { "w" } else { "r+" };
+        let mut file = fs::File::open(local_path)?;
+        let mut guest_file = self.open_file(&Self::guest_file_path(guest_path), mode)?;
+        let mut buffer = vec![0u8; DELTA_BLOCK_SIZE];
+        let mut block = 0;
+
+        loop {
+            self.check_cancelled()?;
+            let n = read_block(&mut file, &mut buffer)?;
+            if n == 0 {
+                break;
+            }
+
+            if signatures.get(block).map(|s| &s.strong) != Some(&hex_sha256(&buffer[..n])) {
+                guest_file.seek(SeekFrom::Start((block * DELTA_BLOCK_SIZE) as u64))?;
+                for piece in buffer[..n].chunks(self.write_chunk_limit()) {
+                    guest_file.write_all(piece)?;
+                }
+            }
+            self.emit(TransferEvent::BytesTransferred { path: event_path.to_string(), bytes: n as u64 });
+            block += 1;
+        }
+        guest_file.close()
+    }
+}
+
+/// Checksums of each `block_size` block `reader` yields
+fn block_signatures(mut reader: impl Read, block_size: usize) -> Result<Vec<BlockSignature>> {
+    let cksum = RollingCksum::new(block_size);
+    let mut buffer = vec![0u8; block_size];
+    let mut signatures = Vec::new();
+    loop {
+        let n = read_block(&mut reader, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        signatures.push(BlockSignature {
+            weak: cksum.finish(cksum.raw(&buffer[..n]), n),
+            strong: hex_sha256(&buffer[..n]),
+            len: n,
+        });
+    }
+    Ok(signatures)
+}
+
+/// Pairs the `CRC LENGTH` lines before `--` with the SHA-256 lines after it
+fn parse_signatures(output: &str) -> Result<Vec<BlockSignature>> {
+    let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+    let weak: Vec<&str> = lines.by_ref().take_while(|l| *l != "--").collect();
+    let strong: Vec<&str> = lines.collect();
+    if weak.len() != strong.len() {
+        anyhow::bail!("{} block checksums but {} block hashes", weak.len(), strong.len());
+    }
+
+    weak.iter()
+        .zip(strong)
+        .map(|(weak, strong)| {
+            let mut fields = weak.split_whitespace();
+            let crc = fields.next().and_then(|f| f.parse().ok());
+            let len = fields.next().and_then(|f| f.parse().ok());
+            let hash = strong.split_whitespace().next().unwrap_or("").to_ascii_lowercase();
+            match (crc, len) {
+                (Some(weak), Some(len)) if hash.len() == 64 => Ok(BlockSignature { weak, strong: hash, len }),
+                _ => anyhow::bail!("Malformed block checksum '{}' / '{}'", weak, strong),
+            }
+        })
+        .collect()
+}
+
+/// Describes the data `reader` yields as copies of the guest blocks in
+/// `signatures` and literal runs. Blocks are found at any offset, as rsync
+/// does, by rolling their `cksum` along the data and confirming candidates
+/// with SHA-256. `check` runs between reads, to stop early.
+fn match_blocks(
+    mut reader: impl Read,
+    signatures: &[BlockSignature],
+    block_size: usize,
+    mut check: impl FnMut() -> Result<()>,
+) -> Result<Vec<DeltaOp>> {
+    let cksum = RollingCksum::new(block_size);
+    let block = block_size as u64;
+
+    // Only whole blocks can match mid-file; a tag table skips most lookups
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    let mut tags = vec![false; 1 << 16];
+    for (index, signature) in signatures.iter().enumerate().filter(|(_, s)| s.len == block_size) {
+        by_weak.entry(signature.weak).or_default().push(index);
+        tags[(signature.weak & 0xffff) as usize] = true;
+    }
+
+    let mut ops = Vec::new();
+    // The local data from `start` on; a block before `pos` is kept for the tail check
+    let mut data = Vec::new();
+    let mut start = 0u64;
+    let mut eof = false;
+    let mut pos = 0u64;
+    let mut literal = 0u64;
+    let mut crc = None;
+
+    loop {
+        while !eof && start + (data.len() as u64) < pos + block + 1 {
+            check()?;
+            let stale = (pos.saturating_sub(block) - start) as usize;
+            if stale >= READ_AHEAD {
+                data.drain(..stale);
+                start += stale as u64;
+            }
+            let filled = data.len();
+            data.resize(filled + READ_AHEAD, 0);
+            let n = read_block(&mut reader, &mut data[filled..])?;
+            data.truncate(filled + n);
+            eof = n < READ_AHEAD;
+        }
+
+        let end = start + data.len() as u64;
+        if pos + block > end {
+            break;
+        }
+
+        let at = (pos - start) as usize;
+        let window = &data[at..at + block_size];
+        let raw = *crc.get_or_insert_with(|| cksum.raw(window));
+        let weak = cksum.finish(raw, block_size);
+        let found = if tags[(weak & 0xffff) as usize] {
+            by_weak.get(&weak).and_then(|candidates| {
+                let strong = hex_sha256(window);
+                let mut matching = candidates.iter().copied().filter(|&i| signatures[i].strong == strong);
+                // Prefer the block after the last copy, so the copies merge
+                let next = match ops.last() {
+                    Some(DeltaOp::Copy { offset, len }) => Some(((offset + len) / block) as usize),
+                    _ => None,
+                };
+                let first = matching.next()?;
+                Some(matching.find(|&i| Some(i) == next).unwrap_or(first))
+            })
+        } else {
+            None
+        };
+
+        match found {
+            Some(index) => {
+                push_literal(&mut ops, literal, pos);
+                push_copy(&mut ops, index as u64 * block, block);
+                pos += block;
+                literal = pos;
+                crc = None;
+            }
+            None => {
+                crc = (pos + block < end).then(|| cksum.roll(raw, data[at], data[at + block_size]));
+                pos += 1;
+            }
+        }
+    }
+
+    // The guest file's short last block can only match the end of the data
+    let end = start + data.len() as u64;
+    if let Some(last) = signatures.last().filter(|s| s.len < block_size) {
+        let tail = last.len as u64;
+        if end - literal >= tail && end - tail >= start {
+            let at = (end - tail - start) as usize;
+            if hex_sha256(&data[at..]) == last.strong {
+                push_literal(&mut ops, literal, end - tail);
+                push_copy(&mut ops, (signatures.len() as u64 - 1) * block, tail);
+                literal = end;
+            }
+        }
+    }
+    push_literal(&mut ops, literal, end);
+    Ok(ops)
+}
+
+fn push_copy(ops: &mut Vec<DeltaOp>, offset: u64, len: u64) {
+    if let Some(DeltaOp::Copy { offset: last, len: last_len }) = ops.last_mut() {
+        if *last + *last_len == offset {
+            *last_len += len;
+            return;
+        }
+    }
+    ops.push(DeltaOp::Copy { offset, len });
+}
+
+fn push_literal(ops: &mut Vec<DeltaOp>, from: u64, to: u64) {
+    if to > from {
+        ops.push(DeltaOp::Literal { offset: from, len: to - from });
+    }
+}
+
+/// The CRC-32 of POSIX `cksum` over a window of fixed size that slides one
+/// byte at a time
+struct RollingCksum {
+    table: [u32; 256],
+    /// What each byte leaving the window contributed to the register
+    outgoing: [u32; 256],
+}
+
+impl RollingCksum {
+    fn new(window: usize) -> Self {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = (i as u32) << 24;
+            for _ in 0..8 {
+                c = if c & 0x8000_0000 != 0 { (c << 1) ^ CKSUM_POLY } else { c << 1 };
+            }
+            *entry = c;
+        }
+
+        let mut cksum = Self { table, outgoing: [0; 256] };
+        // The register is linear in the data, so the effect of a byte followed
+        // by a window of zeros is built from that of its bits
+        let mut bits = [0u32; 8];
+        for (bit, value) in bits.iter_mut().enumerate() {
+            *value = (0..window).fold(cksum.update(0, 1 << bit), |c, _| cksum.update(c, 0));
+        }
+        for byte in 0..256 {
+            cksum.outgoing[byte] = (0..8).filter(|bit| byte & (1 << bit) != 0).fold(0, |c, bit| c ^ bits[bit]);
+        }
+        cksum
+    }
+
+    fn update(&self, crc: u32, byte: u8) -> u32 {
+        (crc << 8) ^ self.table[((crc >> 24) as u8 ^ byte) as usize]
+    }
+
+    /// The register after `data`, before `cksum` appends the length
+    fn raw(&self, data: &[u8]) -> u32 {
+        data.iter().fold(0, |c, &b| self.update(c, b))
+    }
+
+    /// Moves the window one byte on, dropping `out` and taking in `byte`
+    fn roll(&self, crc: u32, out: u8, byte: u8) -> u32 {
+        self.update(crc, byte) ^ self.outgoing[out as usize]
+    }
+
+    /// The value `cksum` prints for `len` bytes that left `crc` in the register
+    fn finish(&self, crc: u32, len: usize) -> u32 {
+        let mut crc = crc;
+        let mut len = len as u64;
+        while len > 0 {
+            crc = self.update(crc, len as u8);
+            len >>= 8;
+        }
+        !crc
+    }
+}
+
+/// Fills `buffer` unless the reader ends first, so blocks stay aligned
+fn read_block(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let n = reader.read(&mut buffer[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic bytes that do not repeat within a test's span
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    /// Builds the new file from `old` and `new` the way the guest does
+    fn apply(ops: &[DeltaOp], old: &[u8], new: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for op in ops {
+            match *op {
+                DeltaOp::Copy { offset, len } => out.extend_from_slice(&old[offset as usize..(offset + len) as usize]),
+                DeltaOp::Literal { offset, len } => out.extend_from_slice(&new[offset as usize..(offset + len) as usize]),
+            }
+        }
+        out
+    }
+
+    fn literal_bytes(ops: &[DeltaOp]) -> u64 {
+        ops.iter()
+            .map(|op| match op {
+                DeltaOp::Literal { len, .. } => *len,
+                DeltaOp::Copy { .. } => 0,
+            })
+            .sum()
+    }
+
+    #[test]
+    fn cksum_matches_posix_cksum() {
+        let cksum = RollingCksum::new(5);
+        // `printf hello | cksum` and `cksum < /dev/null`
+        assert_eq!(cksum.finish(cksum.raw(b"hello"), 5), 3287646509);
+        assert_eq!(cksum.finish(0, 0), 4294967295);
+    }
+
+    #[test]
+    fn rolling_matches_direct_computation() {
+        let bytes = data(600, 1);
+        let cksum = RollingCksum::new(64);
+        let mut crc = cksum.raw(&bytes[..64]);
+        for pos in 1..bytes.len() - 64 {
+            crc = cksum.roll(crc, bytes[pos - 1], bytes[pos + 63]);
+            assert_eq!(crc, cksum.raw(&bytes[pos..pos + 64]), "window at {}", pos);
+        }
+    }
+
+    #[test]
+    fn finds_blocks_after_an_insertion() {
+        let block = 64;
+        let old = data(block * 20 + 17, 2);
+        let mut new = old[..100].to_vec();
+        new.extend_from_slice(b"inserted");
+        new.extend_from_slice(&old[100..]);
+
+        let signatures = block_signatures(&old[..], block).unwrap();
+        let ops = match_blocks(&new[..], &signatures, block, || Ok(())).unwrap();
+        assert_eq!(apply(&ops, &old, &new), new);
+        // Only the block holding the insertion is sent again
+        assert_eq!(literal_bytes(&ops), (block + b"inserted".len()) as u64);
+    }
+
+    #[test]
+    fn unchanged_file_is_one_copy() {
+        let block = 64;
+        let old = data(block * 8 + 5, 3);
+        let signatures = block_signatures(&old[..], block).unwrap();
+        let ops = match_blocks(&old[..], &signatures, block, || Ok(())).unwrap();
+        assert_eq!(ops, vec![DeltaOp::Copy { offset: 0, len: old.len() as u64 }]);
+    }
+
+    #[test]
+    fn unrelated_file_is_all_literal() {
+        let block = 64;
+        let old = data(block * 4, 4);
+        let new = data(block * 3 + 9, 5);
+        let signatures = block_signatures(&old[..], block).unwrap();
+        let ops = match_blocks(&new[..], &signatures, block, || Ok(())).unwrap();
+        assert_eq!(ops, vec![DeltaOp::Literal { offset: 0, len: new.len() as u64 }]);
+    }
+
+    #[test]
+    fn parses_guest_signatures() {
+        let old = data(150, 6);
+        let expected = block_signatures(&old[..], 64).unwrap();
+        let output: String = expected
+            .iter()
+            .map(|s| format!("{} {}\n", s.weak, s.len))
+            .chain(["--\n".to_string()])
+            .chain(expected.iter().map(|s| format!("{}  -\n", s.strong)))
+            .collect();
+        assert_eq!(parse_signatures(&output).unwrap(), expected);
+
+        assert!(parse_signatures("1 64\n--\n").is_err());
+        assert!(parse_signatures("1 64\n--\nnot-a-hash  -\n").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+
+pub type QgaResult<T> = std::result::Result<T, QgaError>;
+
+/// How guest agents word a refusal to open a file: Linux `strerror` texts, and
+/// the Windows messages for access denied, sharing and lock violations
+const REFUSALS: &[&str] = &[
+    "permission denied",
+    "operation not permitted",
+    "access is denied",
+    "being used by another process",
+    "locked a portion of the file",
+];
+
+/// Failures talking to the guest agent, classified so callers can react to them
+#[derive(Debug)]
+pub enum QgaError {
+    /// The agent channel exists but nothing is listening inside the guest
+    AgentNotConnected,
+    /// The agent knows the command but it is disabled or blacklisted in the guest
+    CommandDisabled { command: String },
+    /// The agent does not implement the command at all (older or stripped-down builds)
+    CommandUnsupported { command: String },
+    /// The agent ran the command and reported an error
+    Guest { class: String, desc: String },
+    /// No reply arrived in time
+    Timeout { command: String },
+    /// The command could not be delivered (virsh failed, socket closed, ...)
+    Transport(String),
+    /// The reply was not valid QGA JSON
+    Decode(serde_json::Error),
+    /// The operation was stopped through its [`CancelToken`](crate::cancel::CancelToken)
+    Cancelled,
+}
+
+impl QgaError {
+    /// Classifies an `{"error": {"class": ..., "desc": ...}}` reply
+    pub fn from_guest(command: &str, class: &str, desc: &str) -> Self {
+        if desc.contains("has been disabled") {
+            return Self::CommandDisabled { command: command.to_string() };
+        }
+        if class == "CommandNotFound" {
+            return Self::CommandUnsupported { command: command.to_string() };
+        }
+        Self::Guest {
+            class: class.to_string(),
+            desc: desc.to_string(),
+        }
+    }
+
+    /// Classifies the stderr of a failed `virsh qemu-agent-command`
+    pub fn from_virsh(command: &str, stderr: &str) -> Self {
+        let message = stderr.trim();
+        let lower = message.to_lowercase();
+
+        if lower.contains("not connected") || lower.contains("not configured") {
+            Self::AgentNotConnected
+        } else if lower.contains("has been disabled") {
+            Self::CommandDisabled { command: command.to_string() }
+        } else if lower.contains("not responding") || lower.contains("timed out") || lower.contains("timeout") {
+            Self::Timeout { command: command.to_string() }
+        } else if let Some((_, desc)) = message.split_once("unable to execute QEMU agent command") {
+            // e.g. "error: internal error: unable to execute QEMU agent command 'guest-file-open': failed to open file ..."
+            let desc = desc.split_once(": ").map(|(_, d)| d).unwrap_or(desc);
+            Self::Guest {
+                class: "GenericError".to_string(),
+                desc: desc.trim().to_string(),
+            }
+        } else {
+            Self::Transport(message.to_string())
+        }
+    }
+
+    /// The first `QgaError` in the chain of `err`, including one carried as
+    /// the payload of an `io::Error` from a guest file
+    pub fn find_in(err: &anyhow::Error) -> Option<&QgaError> {
+        err.chain().find_map(|e| {
+            e.downcast_ref::<QgaError>().or_else(|| {
+                e.downcast_ref::<std::io::Error>()
+                    .and_then(|io| io.get_ref())
+                    .and_then(|inner| inner.downcast_ref::<QgaError>())
+            })
+        })
+    }
+
+    /// Whether the guest refused access to a file (permission denied, or held
+    /// open or locked by another process), as opposed to failing to store or
+    /// read it
+    pub fn is_refusal(&self) -> bool {
+        let Self::Guest { desc, .. } = self else {
+            return false;
+        };
+        let desc = desc.to_lowercase();
+        REFUSALS.iter().any(|refusal| desc.contains(refusal))
+    }
+
+    /// Operator-facing advice for the failure, where there is any
+    pub fn hint(&self) -> Option<String> {
+        match self {
+            Self::AgentNotConnected => Some(
+                "Ensure qemu-guest-agent is installed and running in the guest and the VM has a guest agent channel"
+                    .to_string(),
+            ),
+            Self::CommandDisabled { command } => Some(format!(
+                "'{}' is disabled in the guest agent; check its --blacklist/--allow-rpcs settings",
+                command
+            )),
+            Self::CommandUnsupported { command } => Some(format!(
+                "This guest agent does not implement '{}'; a newer qemu-guest-agent may be required",
+                command
+            )),
+            Self::Timeout { .. } => Some("The guest agent did not answer in time; the guest may be busy or hung".to_string()),
+            Self::Transport(_) => Some("Check that virsh works for this VM or that the agent socket path is correct".to_string()),
+            Self::Guest { .. } | Self::Decode(_) | Self::Cancelled => None,
+        }
+    }
+}
+
+impl fmt::Display for QgaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AgentNotConnected => write!(f, "Guest agent is not connected"),
+            Self::CommandDisabled { command } => write!(f, "Guest agent command '{}' is disabled", command),
+            Self::CommandUnsupported { command } => {
+                write!(f, "Guest agent command '{}' is not supported by this agent", command)
+            }
+            Self::Guest { class, desc } => write!(f, "Guest agent error ({}): {}", class, desc),
+            Self::Timeout { command } => write!(f, "Guest agent command '{}' timed out", command),
+            Self::Transport(message) => write!(f, "Transport failure: {}", message),
+            Self::Decode(err) => write!(f, "Failed to parse QEMU response: {}", err),
+            Self::Cancelled => write!(f, "Operation cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for QgaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for QgaError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Decode(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_guest_replies() {
+        assert!(matches!(
+            QgaError::from_guest("guest-exec", "GenericError", "The command guest-exec has been disabled for this instance"),
+            QgaError::CommandDisabled { command } if command == "guest-exec"
+        ));
+        assert!(matches!(
+            QgaError::from_guest("guest-ssh-get-authorized-keys", "CommandNotFound", "The command was not found"),
+            QgaError::CommandUnsupported { .. }
+        ));
+        assert!(matches!(
+            QgaError::from_guest("guest-file-open", "GenericError", "failed to open file '/x': No such file or directory"),
+            QgaError::Guest { class, desc } if class == "GenericError" && desc.contains("No such file")
+        ));
+    }
+
+    #[test]
+    fn classifies_virsh_failures() {
+        let classify = |stderr| QgaError::from_virsh("guest-ping", stderr);
+
+        assert!(matches!(
+            classify("error: Guest agent is not responding: QEMU guest agent is not connected"),
+            QgaError::AgentNotConnected
+        ));
+        assert!(matches!(
+            classify("error: argument unsupported: QEMU guest agent is not configured"),
+            QgaError::AgentNotConnected
+        ));
+        assert!(matches!(
+            classify("error: Guest agent is not responding: Guest agent not available for now"),
+            QgaError::Timeout { .. }
+        ));
+        assert!(matches!(
+            classify("error: internal error: unable to execute QEMU agent command 'guest-exec': The command guest-exec has been disabled for this instance"),
+            QgaError::CommandDisabled { .. }
+        ));
+        assert!(matches!(
+            classify("error: internal error: unable to execute QEMU agent command 'guest-file-open': failed to open file '/x': Permission denied"),
+            QgaError::Guest { desc, .. } if desc == "failed to open file '/x': Permission denied"
+        ));
+        assert!(matches!(
+            classify("error: failed to get domain 'nope'"),
+            QgaError::Transport(message) if message == "error: failed to get domain 'nope'"
+        ));
+    }
+
+    #[test]
+    fn tells_refusals_from_failures() {
+        let guest = |desc: &str| QgaError::from_guest("guest-file-open", "GenericError", desc);
+
+        assert!(guest("failed to open file '/root/x': Permission denied").is_refusal());
+        assert!(guest("failed to open file 'C:\\pagefile.sys': Access is denied.").is_refusal());
+        assert!(guest(
+            "failed to open file 'C:\\x.db': The process cannot access the file because it is being used by another process."
+        )
+        .is_refusal());
+        assert!(!guest("failed to write to file: No space left on device").is_refusal());
+        assert!(!guest("failed to open file '/x/y': No such file or directory").is_refusal());
+        assert!(!guest("failed to read file: Input/output error").is_refusal());
+        assert!(!QgaError::Timeout { command: "guest-file-write".to_string() }.is_refusal());
+    }
+
+    #[test]
+    fn hints_only_where_the_operator_can_act() {
+        assert!(QgaError::AgentNotConnected.hint().is_some());
+        assert!(QgaError::Timeout { command: "guest-ping".to_string() }.hint().is_some());
+        assert!(QgaError::Cancelled.hint().is_none());
+        assert!(QgaError::Guest { class: "GenericError".to_string(), desc: String::new() }.hint().is_none());
+    }
+}
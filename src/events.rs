@@ -0,0 +1,59 @@
+use std::fmt;
+
+/// Progress of a transfer, reported to the agent's [`EventSink`].
+/// `path` is the file being transferred as the caller named it: the local
+/// path for uploads, the guest path for downloads.
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    /// A folder transfer has enumerated its work; `bytes` is known for uploads
+    FolderScanned { files: usize, bytes: Option<u64> },
+    /// A folder transfer completed; `files` were transferred
+    FolderFinished { files: usize },
+    FileStarted { path: String, size: Option<u64> },
+    /// `bytes` more bytes of `path` have been transferred
+    BytesTransferred { path: String, bytes: u64 },
+    FileFinished { path: String },
+    /// The file was left out of a folder transfer
+    Skipped { path: String, reason: String },
+    /// The file was transferred but its SHA-256 differs between host and guest
+    Mismatch { path: String, local: String, guest: String },
+    /// The file failed; the operation returns the error as well
+    Error { path: String, message: String },
+}
+
+/// Receives transfer events. Implemented for closures, so
+/// `agent.set_event_sink(|e: &TransferEvent| ...)` works.
+pub trait EventSink: Send + Sync {
+    fn event(&self, event: &TransferEvent);
+}
+
+impl<F: Fn(&TransferEvent) + Send + Sync> EventSink for F {
+    fn event(&self, event: &TransferEvent) {
+        self(event)
+    }
+}
+
+/// Discards every event; the default sink
+pub struct NullSink;
+
+impl EventSink for NullSink {
+    fn event(&self, _event: &TransferEvent) {}
+}
+
+impl fmt::Display for TransferEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FolderScanned { files, bytes: Some(bytes) } => write!(f, "{} files ({} bytes)", files, bytes),
+            Self::FolderScanned { files, bytes: None } => write!(f, "{} files", files),
+            Self::FolderFinished { files } => write!(f, "{} files transferred", files),
+            Self::FileStarted { path, .. } => write!(f, "{}: started", path),
+            Self::BytesTransferred { path, bytes } => write!(f, "{}: +{} bytes", path, bytes),
+            Self::FileFinished { path } => write!(f, "{}: done", path),
+            Self::Skipped { path, reason } => write!(f, "{}: skipped ({})", path, reason),
+            Self::Mismatch { path, local, guest } => {
+                write!(f, "{}: checksum mismatch (local {}, guest {})", path, local, guest)
+            }
+            Self::Error { path, message } => write!(f, "{}: {}", path, message),
+        }
+    }
+}
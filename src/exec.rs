@@ -0,0 +1,80 @@
+use crate::error::QgaError;
+use crate::qga::{ExecStatus, QemuGuestAgent};
+use crate::transfer::EXEC_COMMANDS;
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Result of a guest process run to completion
+#[derive(Debug, Clone)]
+pub struct ExecOutput {
+    pub exit_code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl ExecOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    fn from_status(status: ExecStatus) -> Result<Self> {
+        let decode = |data: Option<String>| -> Result<Vec<u8>> {
+            data.map(|d| general_purpose::STANDARD.decode(d))
+                .transpose()
+                .map(Option::unwrap_or_default)
+                .context("Failed to decode output")
+        };
+
+        Ok(Self {
+            exit_code: status.exitcode,
+            stdout: decode(status.out_data)?,
+            stderr: decode(status.err_data)?,
+        })
+    }
+}
+
+impl QemuGuestAgent {
+    /// Polls `guest-exec-status` until the process exits or `timeout` elapses.
+    /// Cancelling stops the wait; the guest process keeps running.
+    pub fn wait_for_exec(&self, pid: i64, timeout: Duration) -> Result<ExecStatus> {
+        // A timeout too large to represent means waiting for good
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            let status = self.guest_exec_status(pid)?;
+            if status.exited {
+                return Ok(status);
+            }
+            self.check_cancelled()?;
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(QgaError::Timeout {
+                    command: format!("guest-exec (pid {})", pid),
+                }
+                .into());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Whether the agent can run guest processes and collect their status
+    pub fn can_exec(&self) -> bool {
+        EXEC_COMMANDS.iter().all(|c| self.supports(c))
+    }
+
+    /// Runs a PowerShell script in the guest with captured output
+    pub fn powershell(&self, script: String) -> Result<ExecOutput> {
+        self.exec_output("powershell.exe", &["-NoProfile".to_string(), "-Command".to_string(), script])
+    }
+
+    /// Runs a guest process with captured output and waits for it, bounded by `timeouts.exec`
+    pub fn exec_output(&self, path: &str, args: &[String]) -> Result<ExecOutput> {
+        self.exec_output_with_timeout(path, args, self.timeouts.exec)
+    }
+
+    pub fn exec_output_with_timeout(&self, path: &str, args: &[String], timeout: Duration) -> Result<ExecOutput> {
+        let pid = self.guest_exec(path, Some(args.to_vec()), true)?;
+        let status = self.wait_for_exec(pid, timeout)?;
+        ExecOutput::from_status(status)
+    }
+}
@@ -0,0 +1,381 @@
+use crate::listing::{is_hidden_name, GuestEntry, GuestTree};
+use crate::qga::QemuGuestAgent;
+use crate::transfer::TransferOptions;
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Ignore files honoured with `TransferOptions::ignore_files`, lowest precedence first
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore"];
+
+/// Decides which entries of a folder transfer are sent, from the hidden, glob
+/// and ignore-file options. Paths are relative to the transferred folder and
+/// `/`-separated on both sides.
+pub(crate) struct PathFilter {
+    include_hidden: bool,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    ignore_files: bool,
+    /// Whether ignore files met from now on are read
+    reading_ignore_files: bool,
+    /// Parsed ignore files with the folder they sit in, shallowest first
+    ignores: Vec<(String, Gitignore)>,
+}
+
+impl PathFilter {
+    pub fn new(options: &TransferOptions) -> Result<Self> {
+        let include = if options.include.is_empty() {
+            None
+        } else {
+            Some(glob_set(&options.include)?)
+        };
+
+        Ok(Self {
+            include_hidden: options.include_hidden,
+            include,
+            exclude: glob_set(&options.exclude)?,
+            ignore_files: options.ignore_files,
+            reading_ignore_files: options.ignore_files,
+            ignores: Vec::new(),
+        })
+    }
+
+    /// Whether anything besides hidden entries can be left out
+    pub fn filters_paths(&self) -> bool {
+        self.include.is_some() || !self.exclude.is_empty() || self.ignore_files
+    }
+
+    /// Keeps the ignore rules read so far and reads no further ones, so the
+    /// destination of a sync is filtered by the rules of its source
+    pub fn freeze_ignore_files(&mut self) {
+        self.reading_ignore_files = false;
+    }
+
+    /// Whether `rel` is transferred. Entries below a rejected folder are the
+    /// caller's to drop.
+    pub fn allows(&self, rel: &str, is_dir: bool, hidden: bool) -> bool {
+        if hidden && !self.include_hidden {
+            return false;
+        }
+        if self.exclude.is_match(rel) {
+            return false;
+        }
+
+        // The deepest ignore file with an opinion wins, as in git
+        let path = Path::new("/").join(rel);
+        let ancestors = self
+            .ignores
+            .iter()
+            .rev()
+            .filter(|(dir, _)| dir.is_empty() || rel.starts_with(&format!("{}/", dir)));
+        for (_, gitignore) in ancestors {
+            let matched = gitignore.matched(&path, is_dir);
+            if matched.is_ignore() {
+                return false;
+            }
+            if matched.is_whitelist() {
+                break;
+            }
+        }
+
+        // Includes pick files; folders are kept while they lead to one
+        match &self.include {
+            Some(include) if !is_dir => include.is_match(rel),
+            _ => true,
+        }
+    }
+
+    /// Takes in the rules of an ignore file found in the folder `dir`
+    fn add_ignore_file(&mut self, dir: &str, contents: &str) -> Result<()> {
+        let mut builder = GitignoreBuilder::new(Path::new("/").join(dir));
+        for line in contents.lines() {
+            builder.add_line(None, line)?;
+        }
+
+        let depth = dir.split('/').filter(|c| !c.is_empty()).count();
+        let at = self
+            .ignores
+            .iter()
+            .position(|(d, _)| d.split('/').filter(|c| !c.is_empty()).count() > depth)
+            .unwrap_or(self.ignores.len());
+        self.ignores.insert(at, (dir.to_string(), builder.build()?));
+        Ok(())
+    }
+
+    /// Reads the ignore files of a local folder, when they are honoured
+    fn load_local_ignores(&mut self, folder: &Path, rel: &str) -> Result<()> {
+        if !self.reading_ignore_files {
+            return Ok(());
+        }
+        for name in IGNORE_FILES {
+            let path = folder.join(name);
+            if path.is_file() {
+                let contents =
+                    fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+                self.add_ignore_file(rel, &contents)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks `folder` like `WalkDir`, leaving out what the filter rejects and
+    /// everything below rejected folders. The root comes first, at depth 0;
+    /// unreadable entries are passed on as errors.
+    pub fn walk_local(&mut self, folder: &Path) -> Result<Vec<walkdir::Result<walkdir::DirEntry>>> {
+        let mut error = None;
+        let walker = WalkDir::new(folder).into_iter().filter_entry(|entry| {
+            let rel = entry.path().strip_prefix(folder).map(rel_key).unwrap_or_default();
+            let is_dir = entry.file_type().is_dir();
+            let allowed = entry.depth() == 0
+                || self.allows(&rel, is_dir, is_hidden_name(&entry.file_name().to_string_lossy()));
+            if allowed && is_dir {
+                if let Err(err) = self.load_local_ignores(entry.path(), &rel) {
+                    error.get_or_insert(err);
+                }
+            }
+            allowed
+        });
+
+        let entries: Vec<_> = walker.collect();
+        match error {
+            Some(err) => Err(err),
+            None => Ok(entries),
+        }
+    }
+
+    /// With include patterns, the folders that lead to one of `files`; the
+    /// others would arrive empty and are left out. `None` keeps every folder.
+    pub fn needed_dirs(&self, files: impl IntoIterator<Item = String>) -> Option<HashSet<String>> {
+        self.include.as_ref()?;
+
+        let mut needed = HashSet::new();
+        for file in files {
+            let mut path = file.as_str();
+            while let Some((parent, _)) = path.rsplit_once('/') {
+                if !needed.insert(parent.to_string()) {
+                    break;
+                }
+                path = parent;
+            }
+        }
+        Some(needed)
+    }
+}
+
+impl QemuGuestAgent {
+    /// Drops what `filter` rejects from a listing of `folder`, along with
+    /// everything below rejected folders. When the filter honours ignore files,
+    /// each kept folder's listed ones are read from the guest before its
+    /// contents are filtered; those of rejected folders are never read. Skipped
+    /// entries are kept while the filter would have kept them.
+    pub(crate) fn filter_listing(&self, folder: &str, tree: GuestTree, filter: &mut PathFilter) -> Result<GuestTree> {
+        let GuestTree { entries, skipped } = tree;
+        let mut ignore_files: HashMap<String, Vec<usize>> = HashMap::new();
+        for entry in entries.iter().filter(|e| !e.is_dir) {
+            let (dir, name) = entry.path.rsplit_once('/').unwrap_or(("", &entry.path));
+            if let Some(rank) = IGNORE_FILES.iter().position(|f| *f == name) {
+                ignore_files.entry(dir.to_string()).or_default().push(rank);
+            }
+        }
+
+        // Parents come first in a listing, so a folder's rules are in place
+        // before anything inside it is judged
+        self.load_guest_ignores(folder, "", &mut ignore_files, filter)?;
+        let mut rejected: Vec<String> = Vec::new();
+        let mut kept: Vec<GuestEntry> = Vec::new();
+        for entry in entries {
+            if rejected.iter().any(|dir| entry.path.starts_with(&format!("{}/", dir))) {
+                continue;
+            }
+            if !filter.allows(&entry.path, entry.is_dir, entry.hidden) {
+                if entry.is_dir {
+                    rejected.push(entry.path);
+                }
+                continue;
+            }
+            if entry.is_dir {
+                self.load_guest_ignores(folder, &entry.path, &mut ignore_files, filter)?;
+            }
+            kept.push(entry);
+        }
+
+        let kept_dirs: HashSet<&str> = kept.iter().filter(|e| e.is_dir).map(|e| e.path.as_str()).collect();
+        let skipped = skipped
+            .into_iter()
+            .filter(|(rel, _)| {
+                let (parent, name) = rel.rsplit_once('/').unwrap_or(("", rel));
+                (parent.is_empty() || kept_dirs.contains(parent)) && filter.allows(rel, true, is_hidden_name(name))
+            })
+            .collect();
+
+        let files = kept.iter().filter(|e| !e.is_dir).map(|e| e.path.clone());
+        if let Some(needed) = filter.needed_dirs(files) {
+            kept.retain(|e| !e.is_dir || needed.contains(&e.path));
+        }
+        Ok(GuestTree { entries: kept, skipped })
+    }
+
+    /// Reads the ignore files listed in `dir` of the guest `folder`, when the
+    /// filter honours them
+    fn load_guest_ignores(
+        &self,
+        folder: &str,
+        dir: &str,
+        ignore_files: &mut HashMap<String, Vec<usize>>,
+        filter: &mut PathFilter,
+    ) -> Result<()> {
+        if !filter.reading_ignore_files {
+            return Ok(());
+        }
+        let Some(mut ranks) = ignore_files.remove(dir) else {
+            return Ok(());
+        };
+        ranks.sort();
+
+        for rank in ranks {
+            let rel = if dir.is_empty() {
+                IGNORE_FILES[rank].to_string()
+            } else {
+                format!("{}/{}", dir, IGNORE_FILES[rank])
+            };
+            let contents = self.read_guest_text(&Self::join_guest_path(folder, &rel))?;
+            filter.add_ignore_file(dir, &contents)?;
+        }
+        Ok(())
+    }
+
+    fn read_guest_text(&self, path: &str) -> Result<String> {
+        let normalized = Self::guest_file_path(path);
+
+        let mut contents = String::new();
+        self.open_file(&normalized, "r")?
+            .read_to_string(&mut contents)
+            .with_context(|| format!("Failed to read {}", path))?;
+        Ok(contents)
+    }
+}
+
+/// Glob patterns as rsync reads them: without a `/` they match a name at any
+/// depth, with one they match the path from the transferred folder down
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let trimmed = pattern.trim_end_matches('/');
+        let anchored = if trimmed.contains('/') {
+            trimmed.trim_start_matches('/').to_string()
+        } else {
+            format!("**/{}", trimmed)
+        };
+        let glob = GlobBuilder::new(&anchored)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid pattern '{}'", pattern))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+/// `/`-separated form of a relative path, as listings and filters use
+pub(crate) fn rel_key(rel: &Path) -> String {
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let options = TransferOptions {
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            ignore_files: true,
+            ..TransferOptions::default()
+        };
+        PathFilter::new(&options).unwrap()
+    }
+
+    #[test]
+    fn anchors_patterns_with_a_slash_to_the_folder() {
+        let set = glob_set(&["*.log".to_string(), "logs/".to_string()]).unwrap();
+        assert!(set.is_match("a.log"));
+        assert!(set.is_match("x/y/a.log"));
+        assert!(set.is_match("x/logs"));
+
+        let set = glob_set(&["build/out".to_string(), "/target".to_string(), "src/*.rs".to_string()]).unwrap();
+        assert!(set.is_match("build/out"));
+        assert!(!set.is_match("x/build/out"));
+        assert!(set.is_match("target"));
+        assert!(!set.is_match("x/target"));
+        assert!(set.is_match("src/main.rs"));
+        assert!(!set.is_match("src/bin/main.rs"));
+
+        assert!(glob_set(&["a[".to_string()]).is_err());
+    }
+
+    #[test]
+    fn excludes_then_the_deepest_ignore_file_then_includes_decide() {
+        let mut filter = filter(&["*.tmp", "*.rs"], &["gen.rs", "keep/c.tmp"]);
+        // Added deepest first; they are still consulted deepest first
+        filter.add_ignore_file("keep", "!a.tmp\n!c.tmp\n").unwrap();
+        filter.add_ignore_file("", "*.tmp\n").unwrap();
+
+        assert!(!filter.allows(".env", false, true));
+        assert!(!filter.allows("gen.rs", false, false));
+        assert!(filter.allows("main.rs", false, false));
+        assert!(!filter.allows("b.tmp", false, false));
+        assert!(!filter.allows("a.tmp", false, false));
+        assert!(filter.allows("keep/a.tmp", false, false));
+        assert!(!filter.allows("keep/b.tmp", false, false));
+        assert!(!filter.allows("keep/c.tmp", false, false));
+        assert!(!filter.allows("notes.txt", false, false));
+        assert!(filter.allows("docs", true, false));
+    }
+
+    #[test]
+    fn keeps_only_folders_leading_to_included_files() {
+        assert!(filter(&[], &["*.log"]).needed_dirs(vec!["a/b.txt".to_string()]).is_none());
+
+        let needed = filter(&["*.rs"], &[])
+            .needed_dirs(["a/b/c.rs", "a/d.rs", "e.rs"].map(String::from))
+            .unwrap();
+        assert_eq!(needed, ["a/b", "a"].map(String::from).into_iter().collect());
+    }
+
+    #[test]
+    fn frozen_filters_read_no_further_ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for (side, rule) in [("source", "*.log\n"), ("destination", "*.txt\n")] {
+            let folder = dir.path().join(side);
+            fs::create_dir(&folder).unwrap();
+            fs::write(folder.join(".gitignore"), rule).unwrap();
+            fs::write(folder.join("a.log"), "").unwrap();
+            fs::write(folder.join("a.txt"), "").unwrap();
+        }
+        let names = |filter: &mut PathFilter, side: &str| -> Vec<String> {
+            let folder = dir.path().join(side);
+            let mut names: Vec<String> = filter
+                .walk_local(&folder)
+                .unwrap()
+                .into_iter()
+                .map(|e| rel_key(e.unwrap().path().strip_prefix(&folder).unwrap()))
+                .filter(|rel| !rel.is_empty())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let mut filter = filter(&[], &[]);
+        filter.include_hidden = true;
+        assert_eq!(names(&mut filter, "source"), [".gitignore", "a.txt"]);
+        filter.freeze_ignore_files();
+        assert_eq!(names(&mut filter, "destination"), [".gitignore", "a.txt"]);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::error::QgaError;
+use crate::qga::QemuGuestAgent;
+use anyhow::Result;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Zero-byte `guest-file-write` replies tolerated in a row before giving up
+const MAX_ZERO_WRITES: usize = 3;
+
+/// Largest read the guest agent accepts in a single `guest-file-read`
+const MAX_READ: usize = 48 * 1024 * 1024;
+
+/// An open file in the guest. Implements the std I/O traits on top of the
+/// `guest-file-*` commands and closes the guest handle when dropped.
+pub struct GuestFile<'a> {
+    agent: &'a QemuGuestAgent,
+    handle: i64,
+    closed: bool,
+    /// The agent reported end of file on the last read; saves a round trip
+    eof: bool,
+}
+
+impl QemuGuestAgent {
+    /// Opens `path` in the guest with an `fopen`-style `mode` ("r", "w+", "a", ...)
+    pub fn open_file(&self, path: &str, mode: &str) -> Result<GuestFile<'_>> {
+        let handle = self.guest_file_open(path, mode)?;
+        Ok(GuestFile {
+            agent: self,
+            handle,
+            closed: false,
+            eof: false,
+        })
+    }
+}
+
+impl GuestFile<'_> {
+    pub fn handle(&self) -> i64 {
+        self.handle
+    }
+
+    /// Closes the handle, reporting failures that `Drop` would swallow
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        self.agent.guest_file_close(self.handle)
+    }
+}
+
+impl Read for GuestFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.eof {
+            return Ok(0);
+        }
+
+        let count = buf.len().min(MAX_READ);
+        let (data, eof) = self
+            .agent
+            .guest_file_read(self.handle, count)
+            .map_err(io_error)?;
+        self.eof = eof;
+
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+}
+
+impl Write for GuestFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.eof = false;
+
+        // A short count is returned as is so `write_all` resends the rest; a
+        // zero count is retried here since `write_all` treats it as fatal
+        for _ in 0..MAX_ZERO_WRITES {
+            let count = self
+                .agent
+                .guest_file_write(self.handle, buf)
+                .map_err(io_error)?;
+            if count > buf.len() {
+                return Err(io::Error::other(format!(
+                    "guest reported writing {} bytes of a {} byte chunk",
+                    count,
+                    buf.len()
+                )));
+            }
+            if count > 0 {
+                return Ok(count);
+            }
+        }
+        Ok(0)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.agent
+            .guest_file_flush(self.handle)
+            .map_err(io_error)
+    }
+}
+
+impl Seek for GuestFile<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(offset) => (
+                i64::try_from(offset).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "seek offset too large"))?,
+                "set",
+            ),
+            SeekFrom::Current(offset) => (offset, "cur"),
+            SeekFrom::End(offset) => (offset, "end"),
+        };
+
+        self.eof = false;
+        self.agent
+            .guest_file_seek(self.handle, offset, whence)
+            .map_err(io_error)
+    }
+}
+
+/// Wraps an agent failure for the std I/O traits, keeping a [`QgaError`] as
+/// the payload so [`QgaError::find_in`] still finds it
+fn io_error(err: anyhow::Error) -> io::Error {
+    match err.downcast::<QgaError>() {
+        Ok(qga) => io::Error::other(qga),
+        Err(err) => io::Error::other(err),
+    }
+}
+
+impl Drop for GuestFile<'_> {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.agent.guest_file_close(self.handle);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! A guest agent for tests that need `guest-exec`: guest paths are host paths
+//! and guest processes are host processes, so it stands in for a Linux guest
+
+use crate::error::{QgaError, QgaResult};
+use crate::qga::QemuGuestAgent;
+use crate::transport::MockTransport;
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+pub(crate) struct HostGuest {
+    files: HashMap<i64, fs::File>,
+    processes: HashMap<i64, Output>,
+    next_id: i64,
+    /// Every process started through `guest-exec`, program first
+    pub(crate) exec_log: Vec<Vec<String>>,
+}
+
+impl HostGuest {
+    /// An agent talking to a fresh host guest, and the guest for inspection
+    pub(crate) fn agent() -> (QemuGuestAgent, Arc<Mutex<HostGuest>>) {
+        let guest = Arc::new(Mutex::new(HostGuest::default()));
+        let handler = Arc::clone(&guest);
+        let transport = MockTransport::new(move |command, args| handler.lock().unwrap().handle(command, args));
+        (QemuGuestAgent::with_transport("test".to_string(), Box::new(transport)), guest)
+    }
+
+    fn handle(&mut self, command: &str, args: &Value) -> QgaResult<Value> {
+        let fail = |err: std::io::Error| QgaError::from_guest(command, "GenericError", &err.to_string());
+        let handle = args["handle"].as_i64().unwrap_or_default();
+
+        match command {
+            "guest-info" => {
+                let commands: Vec<Value> = ["guest-file-open", "guest-file-read", "guest-file-write", "guest-file-seek"]
+                    .iter()
+                    .chain(&["guest-file-close", "guest-file-flush", "guest-exec", "guest-exec-status"])
+                    .map(|name| json!({ "name": name, "enabled": true }))
+                    .collect();
+                Ok(json!({ "version": "host", "supported_commands": commands }))
+            }
+            "guest-file-open" => {
+                let path = args["path"].as_str().unwrap();
+                let mut options = fs::OpenOptions::new();
+                match args["mode"].as_str().unwrap() {
+                    "r" => options.read(true),
+                    "r+" => options.read(true).write(true),
+                    "a" => options.append(true).create(true),
+                    _ => options.read(true).write(true).create(true).truncate(true),
+                };
+                let file = options
+                    .open(path)
+                    .map_err(|err| QgaError::from_guest(command, "GenericError", &format!("failed to open file '{}': {}", path, err)))?;
+                self.next_id += 1;
+                self.files.insert(self.next_id, file);
+                Ok(json!(self.next_id))
+            }
+            "guest-file-read" => {
+                let count = args["count"].as_u64().unwrap() as usize;
+                let mut buffer = vec![0u8; count];
+                let n = self.files.get_mut(&handle).unwrap().read(&mut buffer).map_err(fail)?;
+                Ok(json!({
+                    "count": n,
+                    "buf-b64": general_purpose::STANDARD.encode(&buffer[..n]),
+                    "eof": n < count,
+                }))
+            }
+            "guest-file-write" => {
+                let data = general_purpose::STANDARD.decode(args["buf-b64"].as_str().unwrap()).unwrap();
+                self.files.get_mut(&handle).unwrap().write_all(&data).map_err(fail)?;
+                Ok(json!({ "count": data.len(), "eof": false }))
+            }
+            "guest-file-seek" => {
+                let offset = args["offset"].as_i64().unwrap();
+                let from = match args["whence"].as_str().unwrap() {
+                    "set" => SeekFrom::Start(offset as u64),
+                    "cur" => SeekFrom::Current(offset),
+                    _ => SeekFrom::End(offset),
+                };
+                let position = self.files.get_mut(&handle).unwrap().seek(from).map_err(fail)?;
+                Ok(json!({ "position": position, "eof": false }))
+            }
+            "guest-file-close" => {
+                self.files.remove(&handle);
+                Ok(json!({}))
+            }
+            "guest-file-flush" => Ok(json!({})),
+            "guest-exec" => {
+                let program = args["path"].as_str().unwrap().to_string();
+                let arguments: Vec<String> = args["arg"]
+                    .as_array()
+                    .map(|a| a.iter().map(|v| v.as_str().unwrap().to_string()).collect())
+                    .unwrap_or_default();
+                let output = Command::new(&program).args(&arguments).output().map_err(fail)?;
+                self.exec_log.push([vec![program], arguments].concat());
+                self.next_id += 1;
+                self.processes.insert(self.next_id, output);
+                Ok(json!({ "pid": self.next_id }))
+            }
+            "guest-exec-status" => {
+                let output = &self.processes[&args["pid"].as_i64().unwrap()];
+                Ok(json!({
+                    "exited": true,
+                    "exitcode": output.status.code(),
+                    "out-data": general_purpose::STANDARD.encode(&output.stdout),
+                    "err-data": general_purpose::STANDARD.encode(&output.stderr),
+                }))
+            }
+            other => Err(QgaError::CommandUnsupported { command: other.to_string() }),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Library for driving QEMU guest agents: file and folder transfer, clipboard
+//! paste and command execution against Windows or Linux guests.
+//!
+//! ```no_run
+//! use qemu_agent::{QemuGuestAgent, VirshOptions};
+//!
+//! let agent = QemuGuestAgent::new("win11".to_string(), VirshOptions::default());
+//! agent.ping()?;
+//! agent.upload_file("report.pdf".as_ref(), "C:\\Users\\user\\report.pdf")?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+mod archive;
+mod cancel;
+mod capabilities;
+mod checksum;
+mod chunking;
+mod clipboard;
+mod delta;
+mod error;
+mod events;
+mod exec;
+mod filter;
+mod guest_file;
+#[cfg(test)]
+mod host_guest;
+mod listing;
+mod metadata;
+mod qga;
+mod session;
+mod sync;
+mod transfer;
+mod transport;
+
+pub use archive::ArchiveMode;
+pub use cancel::CancelToken;
+pub use capabilities::Capabilities;
+pub use checksum::ChecksumMismatch;
+pub use chunking::{ChunkSize, MAX_CHUNK_SIZE};
+pub use clipboard::PastedItem;
+pub use error::{QgaError, QgaResult};
+pub use events::{EventSink, NullSink, TransferEvent};
+pub use exec::ExecOutput;
+pub use guest_file::GuestFile;
+pub use listing::{GuestEntry, GuestTree};
+pub use metadata::Preserve;
+pub use qga::{ExecStatus, GuestInfo, QemuGuestAgent, SupportedCommand, Timeouts};
+pub use sync::{SyncOptions, SyncReport};
+pub use transfer::{FolderReport, TransferOptions};
+pub use transport::{MockTransport, Privilege, TcpTransport, Transport, UnixSocketTransport, VirshOptions, VirshTransport};
@@ -0,0 +1,329 @@
+use crate::qga::QemuGuestAgent;
+use crate::transfer::EXEC_COMMANDS;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Component, Path, PathBuf};
+
+/// `FILE_ATTRIBUTE_HIDDEN`
+pub(crate) const WINDOWS_HIDDEN: u32 = 0x2;
+
+/// One entry of a guest listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestEntry {
+    /// Path relative to the listed folder, `/`-separated on every guest
+    pub path: String,
+    pub is_dir: bool,
+    /// Size in bytes; 0 for directories
+    pub size: u64,
+    /// Last modification, in whole seconds since the Unix epoch
+    pub mtime: i64,
+    /// Unix permission bits, on Linux guests
+    pub mode: Option<u32>,
+    /// `FileAttributes` flags (read-only, hidden, ...), on Windows guests
+    pub attributes: Option<u32>,
+    /// Numeric owner and group, on Linux guests
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Dotfile, or on Windows guests carries the hidden attribute
+    pub hidden: bool,
+}
+
+/// A recursive guest listing
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GuestTree {
+    /// Parents come before their contents
+    pub entries: Vec<GuestEntry>,
+    /// Entries the listing had to leave out, relative to the listed folder, with
+    /// the reason: folders the guest refused to list, and names that are not
+    /// UTF-8, which guest agent commands cannot carry
+    pub skipped: Vec<(String, String)>,
+}
+
+/// What the Windows listing script prints
+#[derive(Deserialize)]
+struct WindowsListing {
+    entries: Vec<WindowsEntry>,
+    unreadable: Vec<WindowsUnreadable>,
+}
+
+#[derive(Deserialize)]
+struct WindowsEntry {
+    path: String,
+    dir: bool,
+    size: u64,
+    mtime: i64,
+    attributes: u32,
+}
+
+#[derive(Deserialize)]
+struct WindowsUnreadable {
+    path: String,
+    reason: String,
+}
+
+/// How much of a guest path a listing covers
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Depth {
+    /// Just the path itself, listed with an empty relative path
+    Itself,
+    Children,
+    Tree,
+}
+
+impl QemuGuestAgent {
+    /// Lists everything under `folder` in one `guest-exec`: `find -printf` on
+    /// Linux, a `Get-ChildItem` walk printed with `ConvertTo-Json` on Windows.
+    /// Entries other than files and directories (symlinks, junctions, devices)
+    /// are left out, and Windows folders the guest refuses to list are skipped.
+    pub fn list_tree(&self, folder: &str) -> Result<GuestTree> {
+        self.list_entries(folder, Depth::Tree)
+    }
+
+    /// Exact names of the files and folders directly inside `folder`, hidden
+    /// ones included
+    pub fn list_directory(&self, folder: &str) -> Result<Vec<String>> {
+        Ok(self.list_entries(folder, Depth::Children)?.entries.into_iter().map(|e| e.path).collect())
+    }
+
+    /// Type, size, times and permissions of a single guest file or folder
+    pub fn stat_guest(&self, path: &str) -> Result<GuestEntry> {
+        self.list_entries(path, Depth::Itself)?
+            .entries
+            .pop()
+            .with_context(|| format!("{} is neither a file nor a directory", path))
+    }
+
+    fn list_entries(&self, folder: &str, depth: Depth) -> Result<GuestTree> {
+        self.require_commands(EXEC_COMMANDS)
+            .with_context(|| format!("Cannot list guest directory {}", folder))?;
+
+        if Self::is_windows_path(folder) {
+            self.list_entries_windows(folder, depth)
+        } else {
+            self.list_entries_unix(folder, depth)
+        }
+    }
+
+    fn list_entries_unix(&self, folder: &str, depth: Depth) -> Result<GuestTree> {
+        let mut args = vec![folder.to_string()];
+        match depth {
+            Depth::Itself => args.extend(["-maxdepth".to_string(), "0".to_string()]),
+            Depth::Children => args.extend(["-mindepth", "1", "-maxdepth", "1"].map(String::from)),
+            Depth::Tree => args.extend(["-mindepth".to_string(), "1".to_string()]),
+        }
+        args.extend(["-printf".to_string(), "%y\\t%s\\t%T@\\t%m\\t%U\\t%G\\t%P\\0".to_string()]);
+        let output = self.exec_output("find", &args)?;
+
+        if !output.success() {
+            anyhow::bail!(
+                "Failed to list {}: {}",
+                folder,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        parse_find_listing(folder, &output.stdout)
+    }
+
+    fn list_entries_windows(&self, folder: &str, depth: Depth) -> Result<GuestTree> {
+        let literal = folder.replace('\'', "''");
+        // Folders are listed one at a time, so one the guest refuses is
+        // recorded and skipped instead of ending the walk. Junctions and
+        // symbolic links to folders are not followed, which also keeps loops
+        // out.
+        let walk = match depth {
+            Depth::Itself => "Add-Entry $top".to_string(),
+            Depth::Children | Depth::Tree => format!(
+                "$pending = [Collections.Generic.Queue[string]]::new(); $pending.Enqueue($top.FullName); \
+                 while ($pending.Count -gt 0) {{ \
+                     $dir = $pending.Dequeue(); \
+                     try {{ $children = @(Get-ChildItem -LiteralPath $dir -Force) }} \
+                     catch {{ $unreadable.Add([pscustomobject]@{{ path = Rel $dir; reason = $_.Exception.Message }}); continue }}; \
+                     foreach ($c in $children) {{ \
+                         if ($c.PSIsContainer -and ($c.Attributes -band [IO.FileAttributes]::ReparsePoint)) {{ continue }}; \
+                         Add-Entry $c; \
+                         if (${recurse} -and $c.PSIsContainer) {{ $pending.Enqueue($c.FullName) }} \
+                     }} \
+                 }}",
+                recurse = depth == Depth::Tree,
+            ),
+        };
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             [Console]::OutputEncoding = [Text.Encoding]::UTF8; \
+             $top = Get-Item -LiteralPath '{literal}' -Force; \
+             $root = $top.FullName.TrimEnd('\\'); \
+             $entries = [Collections.Generic.List[object]]::new(); \
+             $unreadable = [Collections.Generic.List[object]]::new(); \
+             function Rel($p) {{ $p.Substring([Math]::Min($root.Length + 1, $p.Length)).Replace('\\', '/') }}; \
+             function Add-Entry($i) {{ \
+                 $entries.Add([pscustomobject]@{{ \
+                     path = Rel $i.FullName; \
+                     dir = $i.PSIsContainer; \
+                     size = $(if ($i.PSIsContainer) {{ 0 }} else {{ $i.Length }}); \
+                     mtime = ([DateTimeOffset]$i.LastWriteTimeUtc).ToUnixTimeSeconds(); \
+                     attributes = [int]$i.Attributes \
+                 }}) \
+             }}; \
+             {walk}; \
+             ConvertTo-Json -Compress -Depth 4 -InputObject @{{ entries = @($entries); unreadable = @($unreadable) }}"
+        );
+        let output = self.powershell(script)?;
+
+        if !output.success() {
+            anyhow::bail!(
+                "Failed to list {}: {}",
+                folder,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        let listing: WindowsListing = serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("Failed to parse the listing of {}", folder))?;
+        if let Some(root) = listing.unreadable.iter().find(|u| u.path.is_empty()) {
+            anyhow::bail!("Failed to list {}: {}", folder, root.reason);
+        }
+
+        Ok(GuestTree {
+            entries: listing
+                .entries
+                .into_iter()
+                .map(|e| GuestEntry {
+                    path: e.path,
+                    is_dir: e.dir,
+                    size: e.size,
+                    mtime: e.mtime,
+                    mode: None,
+                    attributes: Some(e.attributes),
+                    uid: None,
+                    gid: None,
+                    hidden: e.attributes & WINDOWS_HIDDEN != 0,
+                })
+                .collect(),
+            skipped: listing.unreadable.into_iter().map(|u| (u.path, u.reason)).collect(),
+        })
+    }
+}
+
+/// Parses the NUL-terminated `%y\t%s\t%T@\t%m\t%U\t%G\t%P` records `find -printf`
+/// prints for `folder`. Names may hold any byte but NUL, tabs and newlines
+/// included; those that are not UTF-8 are skipped.
+fn parse_find_listing(folder: &str, stdout: &[u8]) -> Result<GuestTree> {
+    let mut tree = GuestTree::default();
+    for record in stdout.split(|&b| b == 0).filter(|r| !r.is_empty()) {
+        let fields: Vec<&[u8]> = record.splitn(7, |&b| b == b'\t').collect();
+        let &[kind, size, mtime, mode, uid, gid, path] = fields.as_slice() else {
+            anyhow::bail!("Unexpected listing line for {}: {:?}", folder, String::from_utf8_lossy(record));
+        };
+        let field = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+
+        let is_dir = match kind {
+            b"d" => true,
+            b"f" => false,
+            _ => continue,
+        };
+        let Ok(path) = String::from_utf8(path.to_vec()) else {
+            tree.skipped.push((
+                field(path),
+                "name is not valid UTF-8, which guest agent commands cannot carry".to_string(),
+            ));
+            continue;
+        };
+        let hidden = if path.is_empty() { folder } else { &path }
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .is_some_and(is_hidden_name);
+
+        tree.entries.push(GuestEntry {
+            is_dir,
+            size: if is_dir { 0 } else { field(size).parse().unwrap_or(0) },
+            mtime: field(mtime).parse::<f64>().map(|t| t.floor() as i64).unwrap_or(0),
+            mode: u32::from_str_radix(&field(mode), 8).ok(),
+            attributes: None,
+            uid: field(uid).parse().ok(),
+            gid: field(gid).parse().ok(),
+            hidden,
+            path,
+        });
+    }
+    Ok(tree)
+}
+
+/// Where the listed entry `rel` goes below the local `folder`. The path comes
+/// from the guest, so anything that would land outside `folder` (absolute
+/// paths, `..`) is refused.
+pub(crate) fn local_entry_path(folder: &Path, rel: &str) -> Result<PathBuf> {
+    let path = Path::new(rel);
+    if rel.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        anyhow::bail!("Refusing guest path {:?}: it would land outside {}", rel, folder.display());
+    }
+    Ok(folder.join(path))
+}
+
+/// Dotfiles, as hidden entries are named on Linux and in local folders
+pub(crate) fn is_hidden_name(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(kind: &str, path: &[u8]) -> Vec<u8> {
+        let mut record = format!("{}\t12\t1700000000.75\t644\t1000\t100\t", kind).into_bytes();
+        record.extend_from_slice(path);
+        record.push(0);
+        record
+    }
+
+    #[test]
+    fn parses_odd_names_from_find() {
+        let stdout: Vec<u8> = [
+            record("d", b" leading space"),
+            record("f", b" leading space/line\nbreak.txt"),
+            record("f", b"tab\there"),
+            record("f", b".hidden"),
+            record("l", b"symlink"),
+            record("f", b"latin1-\xe9t\xe9.txt"),
+        ]
+        .concat();
+
+        let tree = parse_find_listing("/srv/data", &stdout).unwrap();
+        let paths: Vec<&str> = tree.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, [" leading space", " leading space/line\nbreak.txt", "tab\there", ".hidden"]);
+
+        let file = &tree.entries[1];
+        assert!(!file.is_dir && !file.hidden);
+        assert_eq!((file.size, file.mtime, file.mode, file.uid, file.gid), (12, 1_700_000_000, Some(0o644), Some(1000), Some(100)));
+        assert_eq!(tree.entries[0].size, 0, "folders have no size");
+        assert!(tree.entries[3].hidden);
+
+        assert_eq!(tree.skipped.len(), 1);
+        assert_eq!(tree.skipped[0].0, "latin1-\u{fffd}t\u{fffd}.txt");
+    }
+
+    #[test]
+    fn names_the_listed_folder_itself_with_an_empty_path() {
+        let tree = parse_find_listing("/home/user/.config/", &record("d", b"")).unwrap();
+        assert_eq!(tree.entries[0].path, "");
+        assert!(tree.entries[0].hidden);
+    }
+
+    #[test]
+    fn keeps_guest_paths_inside_the_destination() {
+        let folder = Path::new("/home/user/download");
+        assert_eq!(local_entry_path(folder, "a/b.txt").unwrap(), folder.join("a/b.txt"));
+        assert_eq!(local_entry_path(folder, " x/..y").unwrap(), folder.join(" x/..y"));
+        assert!(local_entry_path(folder, "../escape").is_err());
+        assert!(local_entry_path(folder, "a/../../escape").is_err());
+        assert!(local_entry_path(folder, "/etc/passwd").is_err());
+        assert!(local_entry_path(folder, "./a").is_err());
+        assert!(local_entry_path(folder, "").is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        assert!(parse_find_listing("/srv", b"f\t12\t1700000000\0").is_err());
+    }
+}
@@ -1,82 +1,255 @@
 use anyhow::{Context, Result};
-use base64::{engine::general_purpose, Engine as _};
 use clap::Parser;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
 mod colors;
 mod cli;
-mod qga;
-mod transfer;
-mod clipboard;
+mod progress;
+mod raw;
 
 use cli::{Cli, Commands};
 use colors::Tags;
-use qga::QemuGuestAgent;
+use progress::ProgressRenderer;
+use qemu_agent::{
+    ChecksumMismatch, PastedItem, QemuGuestAgent, QgaError, SyncOptions, TcpTransport, Timeouts, UnixSocketTransport,
+    VirshOptions,
+};
+
+/// Exit status of `wait` when the agent never became ready, matching coreutils `timeout`
+const WAIT_TIMEOUT_EXIT_CODE: i32 = 124;
+/// Exit status after Ctrl+C, as shells report SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let agent = QemuGuestAgent::new(cli.vm_name);
+    let mut agent = if let Some(path) = cli.socket {
+        QemuGuestAgent::with_transport(cli.vm_name, Box::new(UnixSocketTransport::new(path)))
+    } else if let Some(address) = cli.tcp {
+        QemuGuestAgent::with_transport(cli.vm_name, Box::new(TcpTransport::new(address)))
+    } else {
+        let options = VirshOptions {
+            connect: cli.connect,
+            privilege: cli.privilege,
+            virsh: cli.virsh,
+        };
+        QemuGuestAgent::new(cli.vm_name, options)
+    };
+    agent.timeouts = Timeouts {
+        command: cli.agent_timeout,
+        exec: cli.exec_timeout,
+        archive: cli.archive_timeout,
+        ..Timeouts::default()
+    };
+    agent.chunk_size = cli.chunk_size;
+    let progress = ProgressRenderer::default();
+    agent.set_event_sink(progress.clone());
 
-    // Set up Ctrl+C handler for graceful shutdown
-    let running = Arc::new(AtomicBool::new(true));
-    let running_clone = running.clone();
+    // First Ctrl+C cancels and lets transfers clean up, a second one exits at once
+    let cancel = agent.cancel_token();
+    let handler_cancel = cancel.clone();
     ctrlc::set_handler(move || {
-        running_clone.store(false, Ordering::SeqCst);
-        println!("\n{} Cleaning up...", Tags::interrupt());
+        if handler_cancel.is_cancelled() {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        handler_cancel.cancel();
+        eprintln!("\n{} Cleaning up...", Tags::interrupt());
     }).context("Error setting Ctrl-C handler")?;
 
-    match cli.command {
-        Commands::Upload { source, dest } => {
+    // `wait` does its own polling, pinging first would defeat it
+    let skip_ping = cli.no_ping || matches!(cli.command, Commands::Wait { .. });
+    let result = if skip_ping {
+        run(&agent, cli.command)
+    } else {
+        agent.ping().map_err(anyhow::Error::from).and_then(|_| run(&agent, cli.command))
+    };
+    progress.finish();
+
+    if result.is_err() && cancel.is_cancelled() {
+        let finished = progress.finished();
+        eprintln!("{} Cancelled; {} files completed", Tags::interrupt(), finished.len());
+        for path in finished {
+            eprintln!("  {}", path);
+        }
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+
+    if let Err(err) = &result {
+        let hint = QgaError::find_in(err).and_then(QgaError::hint);
+        if let Some(hint) = hint {
+            eprintln!("{} {}: {}", Tags::warn(), agent.vm_name, hint);
+        }
+    }
+    result
+}
+
+fn run(agent: &QemuGuestAgent, command: Commands) -> Result<()> {
+    match command {
+        Commands::Upload { source, dest, transfer } => {
+            let options = transfer.options()?;
             if source.is_file() {
                 println!("{} Uploading file: {} -> {}", Tags::upload(), source.display(), dest);
-                agent.upload_file(&source, &dest)?;
+                agent.upload_file_with(&source, &dest, &options)?;
                 println!("{} Upload complete", Tags::ok());
             } else if source.is_dir() {
-                agent.upload_folder(&source, &dest)?;
+                println!("{} Uploading folder: {} -> {}", Tags::upload(), source.display(), dest);
+                let report = agent.upload_folder_with(&source, &dest, &options)?;
+                check_complete(&report.skipped, &report.mismatched)?;
+                println!("{} Upload complete ({} files)", Tags::ok(), report.files);
             } else {
                 anyhow::bail!("Source path does not exist: {}", source.display());
             }
         }
-        Commands::Download { source, dest } => {
+        Commands::Download { source, dest, transfer } => {
+            let options = transfer.options()?;
             if agent.check_is_directory(&source)? {
-                agent.download_folder(&source, &dest)?;
+                println!("{} Downloading folder: {} -> {}", Tags::download(), source, dest.display());
+                let report = agent.download_folder_with(&source, &dest, &options)?;
+                check_complete(&report.skipped, &report.mismatched)?;
+                println!("{} Folder downloaded successfully ({} files)", Tags::ok(), report.files);
             } else {
                 println!("{} Downloading file: {} -> {}", Tags::download(), source, dest.display());
-                agent.download_file(&source, &dest)?;
+                agent.download_file_with(&source, &dest, &options)?;
                 println!("{} Download complete", Tags::ok());
             }
         }
+        Commands::Sync { local, guest, pull, checksum, delete, transfer } => {
+            let options = SyncOptions {
+                checksum,
+                delete,
+                transfer: transfer.options()?,
+            };
+            let report = if pull {
+                println!("{} Syncing {} -> {}", Tags::sync(), guest, local.display());
+                agent.sync_from_guest(&guest, &local, &options)?
+            } else {
+                if !local.is_dir() {
+                    anyhow::bail!("Source folder does not exist: {}", local.display());
+                }
+                println!("{} Syncing {} -> {}", Tags::sync(), local.display(), guest);
+                agent.sync_to_guest(&local, &guest, &options)?
+            };
+
+            for path in &report.deleted {
+                println!("{} Deleted {}", Tags::sync(), path.display());
+            }
+            check_complete(&report.skipped, &report.mismatched)?;
+            println!(
+                "{} Sync complete ({} transferred, {} unchanged, {} deleted)",
+                Tags::ok(),
+                report.transferred.len(),
+                report.unchanged,
+                report.deleted.len()
+            );
+        }
         Commands::Paste { source, dest } => {
-            agent.paste_clipboard(source, dest)?;
+            if source.is_none() {
+                println!("{} Reading clipboard...", Tags::clip());
+            }
+            let mut skipped = Vec::new();
+            let mut mismatched = Vec::new();
+            for item in agent.paste_clipboard(source, dest)? {
+                match item {
+                    PastedItem::File { source, guest_path } => {
+                        println!("{} Uploaded file: {} -> {}", Tags::file(), source.display(), guest_path);
+                    }
+                    PastedItem::Folder { source, guest_path, report } => {
+                        println!(
+                            "{} Uploaded folder: {} -> {} ({} files)",
+                            Tags::folder(),
+                            source.display(),
+                            guest_path,
+                            report.files
+                        );
+                        skipped.extend(report.skipped.into_iter().map(|(path, reason)| (source.join(path), reason)));
+                        mismatched.extend(report.mismatched);
+                    }
+                    PastedItem::Text { bytes, guest_path } => {
+                        println!("{} Pasted {} bytes of text to: {}", Tags::text(), bytes, guest_path);
+                    }
+                    PastedItem::Missing { path } => {
+                        println!("{} Non-existent path: {}", Tags::skip(), path);
+                    }
+                }
+            }
+            check_complete(&skipped, &mismatched)?;
+            println!("{} Clipboard pasted successfully", Tags::ok());
         }
         Commands::Exec { command, args } => {
             println!("{} Executing: {} {:?}", Tags::exec(), command, args);
-            let pid = agent.guest_exec(&command, Some(args), true)?;
-
-            // Wait for completion
-            for _ in 0..30 {
-                let status = agent.guest_exec_status(pid)?;
-                if status.exited {
-                    println!("Exit code: {}", status.exitcode.unwrap_or(-1));
+            let output = agent.exec_output(&command, &args)?;
 
-                    if let Some(out_data) = status.out_data {
-                        let output = general_purpose::STANDARD.decode(out_data)?;
-                        println!("Output:\n{}", String::from_utf8_lossy(&output));
-                    }
+            println!("Exit code: {}", output.exit_code.unwrap_or(-1));
+            println!("Output:\n{}", String::from_utf8_lossy(&output.stdout));
+            if !output.stderr.is_empty() {
+                eprintln!("Error:\n{}", String::from_utf8_lossy(&output.stderr));
+            }
+        }
+        Commands::Raw { command, args, file } => {
+            if let Some(path) = file {
+                let reader: Box<dyn BufRead> = if path.as_os_str() == "-" {
+                    Box::new(io::stdin().lock())
+                } else {
+                    let file = fs::File::open(&path)
+                        .with_context(|| format!("Failed to open {}", path.display()))?;
+                    Box::new(io::BufReader::new(file))
+                };
 
-                    if let Some(err_data) = status.err_data {
-                        let output = general_purpose::STANDARD.decode(err_data)?;
-                        eprintln!("Error:\n{}", String::from_utf8_lossy(&output));
+                for line in reader.lines() {
+                    let line = line?;
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
                     }
-
-                    break;
+                    let (command, arguments) = raw::parse_raw_command(line)?;
+                    println!("{} {}", Tags::raw(), command);
+                    print_raw_result(agent, &command, arguments)?;
                 }
-                thread::sleep(Duration::from_millis(100));
+            } else if let Some(command) = command {
+                let (command, arguments) = raw::parse_raw_arguments(&command, args.as_deref().unwrap_or(""))?;
+                print_raw_result(agent, &command, arguments)?;
+            }
+        }
+        Commands::Wait { timeout, info, require } => {
+            println!("{} Waiting up to {}s for the guest agent...", Tags::wait(), timeout.as_secs());
+            match agent.wait_until_ready(timeout, info, &require) {
+                Ok(Some(guest_info)) => {
+                    println!("{} Guest agent ready (version {})", Tags::ok(), guest_info.version);
+                }
+                Ok(None) => println!("{} Guest agent ready", Tags::ok()),
+                Err(err) if matches!(QgaError::find_in(&err), Some(QgaError::Timeout { .. })) => {
+                    eprintln!("{} Guest agent not ready after {}s", Tags::warn(), timeout.as_secs());
+                    std::process::exit(WAIT_TIMEOUT_EXIT_CODE);
+                }
+                Err(err) => return Err(err),
             }
         }
     }
 
     Ok(())
 }
+
+/// Fails a folder transfer that left files out or in which any file did not
+/// verify; each mismatch was already printed, the skipped files are listed here
+fn check_complete(skipped: &[(PathBuf, String)], mismatched: &[ChecksumMismatch]) -> Result<()> {
+    if !skipped.is_empty() {
+        eprintln!("{} {} files were skipped:", Tags::skip(), skipped.len());
+        for (path, reason) in skipped {
+            eprintln!("  {}: {}", path.display(), reason);
+        }
+    }
+    if !mismatched.is_empty() {
+        anyhow::bail!("{} files failed checksum verification", mismatched.len());
+    }
+    if !skipped.is_empty() {
+        anyhow::bail!("{} files were skipped", skipped.len());
+    }
+    Ok(())
+}
+
+fn print_raw_result(agent: &QemuGuestAgent, command: &str, arguments: serde_json::Value) -> Result<()> {
+    let result = agent.run_command(command, arguments)?;
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
@@ -0,0 +1,406 @@
+use crate::listing::{is_hidden_name, GuestEntry, WINDOWS_HIDDEN};
+use crate::qga::QemuGuestAgent;
+use crate::transfer::{FileJob, EXEC_COMMANDS};
+use anyhow::{Context, Result};
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// `FILE_ATTRIBUTE_READONLY`
+const WINDOWS_READONLY: u32 = 0x1;
+
+/// Longest script handed to one `guest-exec`; PowerShell command lines stop at 32K
+const MAX_SCRIPT_LEN: usize = 24 * 1024;
+
+/// Which file metadata transfers carry over to the destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Preserve {
+    /// Modification times
+    pub times: bool,
+    /// Unix permission bits, including the executable bit
+    pub mode: bool,
+    /// Read-only, and hidden in Windows guests, where uploaded dotfiles are
+    /// marked hidden; a Linux host has no hidden attribute to keep
+    pub attributes: bool,
+    /// Numeric owner and group, between Linux guests and the host
+    pub owner: bool,
+}
+
+impl Preserve {
+    pub fn any(&self) -> bool {
+        self.times || self.mode || self.attributes || self.owner
+    }
+}
+
+impl FromStr for Preserve {
+    type Err = anyhow::Error;
+
+    /// Comma-separated `times`, `mode`, `attrs` and `owner`, or `all`
+    fn from_str(s: &str) -> Result<Self> {
+        let mut preserve = Self::default();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            match item {
+                "times" => preserve.times = true,
+                "mode" => preserve.mode = true,
+                "attrs" | "attributes" => preserve.attributes = true,
+                "owner" => preserve.owner = true,
+                "all" => {
+                    preserve = Self {
+                        times: true,
+                        mode: true,
+                        attributes: true,
+                        owner: true,
+                    }
+                }
+                other => anyhow::bail!("Unknown metadata '{}' (expected times, mode, attrs, owner or all)", other),
+            }
+        }
+        Ok(preserve)
+    }
+}
+
+/// Metadata of one file or folder, in a form both sides understand
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FileMetadata {
+    pub is_dir: bool,
+    /// Seconds since the Unix epoch
+    pub mtime: i64,
+    pub mode: Option<u32>,
+    pub readonly: bool,
+    pub hidden: bool,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FileMetadata {
+    pub fn local(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path).with_context(|| format!("Failed to read metadata of {}", path.display()))?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+
+        Ok(Self {
+            is_dir: metadata.is_dir(),
+            mtime,
+            mode: Some(metadata.permissions().mode() & 0o7777),
+            readonly: metadata.permissions().readonly(),
+            hidden: path.file_name().is_some_and(|n| is_hidden_name(&n.to_string_lossy())),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+        })
+    }
+}
+
+impl From<&GuestEntry> for FileMetadata {
+    fn from(entry: &GuestEntry) -> Self {
+        Self {
+            is_dir: entry.is_dir,
+            mtime: entry.mtime,
+            mode: entry.mode,
+            // Linux guests express read-only through the mode
+            readonly: entry.attributes.is_some_and(|a| a & WINDOWS_READONLY != 0),
+            hidden: entry.hidden,
+            uid: entry.uid,
+            gid: entry.gid,
+        }
+    }
+}
+
+impl QemuGuestAgent {
+    /// Applies `metadata` to guest paths, batching many paths into each
+    /// `guest-exec`: `chown`/`chmod`/`touch -d` on Linux, `Get-Item` property
+    /// assignments on Windows. Pass folders after their contents, since writing
+    /// a file changes its folder's modification time.
+    pub(crate) fn apply_guest_metadata(&self, items: &[(String, FileMetadata)], preserve: &Preserve) -> Result<()> {
+        if !preserve.any() || items.is_empty() {
+            return Ok(());
+        }
+        self.require_commands(EXEC_COMMANDS)
+            .context("Preserving metadata in the guest needs guest-exec")?;
+
+        let windows = Self::is_windows_path(&items[0].0);
+        let commands: Vec<String> = items
+            .iter()
+            .map(|(path, metadata)| {
+                if windows {
+                    windows_metadata_script(path, metadata, preserve)
+                } else {
+                    unix_metadata_script(path, metadata, preserve)
+                }
+            })
+            .filter(|c| !c.is_empty())
+            .collect();
+
+        let mut batch = String::new();
+        for command in commands {
+            if !batch.is_empty() && batch.len() + command.len() > MAX_SCRIPT_LEN {
+                self.run_metadata_script(&batch, windows)?;
+                batch.clear();
+            }
+            batch.push_str(&command);
+            batch.push('\n');
+        }
+        if !batch.is_empty() {
+            self.run_metadata_script(&batch, windows)?;
+        }
+        Ok(())
+    }
+
+    /// Gives the guest copies of an uploaded folder's `files`, `dirs` and the
+    /// folder itself the metadata of their local originals
+    pub(crate) fn preserve_uploaded_folder(
+        &self,
+        local_folder: &Path,
+        guest_folder: &str,
+        files: &[&FileJob],
+        dirs: &[FileJob],
+        preserve: &Preserve,
+    ) -> Result<()> {
+        if !preserve.any() {
+            return Ok(());
+        }
+
+        let mut items = Vec::new();
+        for job in files.iter().copied().chain(dirs.iter().rev()) {
+            items.push((job.guest.clone(), FileMetadata::local(&job.local)?));
+        }
+        items.push((guest_folder.to_string(), FileMetadata::local(local_folder)?));
+        self.apply_guest_metadata(&items, preserve)
+    }
+
+    /// Gives a downloaded folder's `files`, `dirs` and the folder itself the
+    /// metadata listed for them in the guest
+    pub(crate) fn preserve_downloaded_folder(
+        &self,
+        guest_folder: &str,
+        local_folder: &Path,
+        files: &[&GuestEntry],
+        dirs: &[&GuestEntry],
+        preserve: &Preserve,
+    ) -> Result<()> {
+        if !preserve.any() {
+            return Ok(());
+        }
+
+        for entry in files.iter().chain(dirs.iter().rev()) {
+            self.check_cancelled()?;
+            apply_local_metadata(&local_folder.join(&entry.path), &FileMetadata::from(*entry), preserve)?;
+        }
+        let root = self.stat_guest(guest_folder)?;
+        apply_local_metadata(local_folder, &FileMetadata::from(&root), preserve)
+    }
+
+    fn run_metadata_script(&self, script: &str, windows: bool) -> Result<()> {
+        self.check_cancelled()?;
+        let output = if windows {
+            let script = format!("$ErrorActionPreference = 'Stop'\n{}", script);
+            self.powershell(script)?
+        } else {
+            self.exec_output("sh", &["-ec".to_string(), script.to_string()])?
+        };
+
+        if !output.success() {
+            anyhow::bail!(
+                "Failed to preserve metadata in the guest: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn unix_metadata_script(path: &str, metadata: &FileMetadata, preserve: &Preserve) -> String {
+    let quoted = format!("'{}'", path.replace('\'', "'\\''"));
+    let mut commands = Vec::new();
+
+    // chown clears setuid bits, so it goes before chmod
+    if preserve.owner {
+        if let (Some(uid), Some(gid)) = (metadata.uid, metadata.gid) {
+            commands.push(format!("chown {}:{} -- {}", uid, gid, quoted));
+        }
+    }
+    match metadata.mode.filter(|_| preserve.mode) {
+        Some(mode) => commands.push(format!("chmod {:o} -- {}", mode, quoted)),
+        None if preserve.attributes && metadata.readonly && !metadata.is_dir => {
+            commands.push(format!("chmod a-w -- {}", quoted));
+        }
+        None => {}
+    }
+    if preserve.times {
+        commands.push(format!("touch -m -d @{} -- {}", metadata.mtime, quoted));
+    }
+    commands.join("\n")
+}
+
+fn windows_metadata_script(path: &str, metadata: &FileMetadata, preserve: &Preserve) -> String {
+    if !preserve.times && !preserve.attributes {
+        return String::new();
+    }
+
+    let mut script = format!("$i = Get-Item -LiteralPath '{}' -Force", path.replace('\'', "''"));
+    if preserve.times {
+        script.push_str(&format!(
+            "; $i.LastWriteTimeUtc = [DateTimeOffset]::FromUnixTimeSeconds({}).UtcDateTime",
+            metadata.mtime
+        ));
+    }
+    if preserve.attributes {
+        let mut flags = 0;
+        // Read-only on a folder only marks it as customized in Explorer
+        if metadata.readonly && !metadata.is_dir {
+            flags |= WINDOWS_READONLY;
+        }
+        if metadata.hidden {
+            flags |= WINDOWS_HIDDEN;
+        }
+        script.push_str(&format!(
+            "; $a = ([int]$i.Attributes -band -bnot {}) -bor {}; if ($a -eq 0) {{ $a = 128 }}; $i.Attributes = $a",
+            WINDOWS_READONLY | WINDOWS_HIDDEN,
+            flags
+        ));
+    }
+    script
+}
+
+/// Applies metadata from the guest to a local file or folder. Folders go after
+/// their contents, as with `apply_guest_metadata`.
+pub(crate) fn apply_local_metadata(path: &Path, metadata: &FileMetadata, preserve: &Preserve) -> Result<()> {
+    let context = || format!("Failed to preserve metadata on {}", path.display());
+
+    if preserve.owner {
+        if let (Some(uid), Some(gid)) = (metadata.uid, metadata.gid) {
+            std::os::unix::fs::chown(path, Some(uid), Some(gid)).with_context(context)?;
+        }
+    }
+    if preserve.times {
+        // Setting times needs ownership, not write access, so a read-only handle will do
+        let mtime = UNIX_EPOCH + Duration::from_secs(metadata.mtime.max(0) as u64);
+        fs::File::open(path)
+            .and_then(|f| f.set_modified(mtime))
+            .with_context(context)?;
+    }
+
+    let mut permissions = fs::metadata(path).with_context(context)?.permissions();
+    let original = permissions.clone();
+    if preserve.mode {
+        if let Some(mode) = metadata.mode {
+            permissions.set_mode(mode);
+        }
+    }
+    // Windows guests have no mode; read-only clears or restores the write bits
+    if preserve.attributes && !metadata.is_dir && metadata.mode.is_none() {
+        let mode = permissions.mode();
+        permissions.set_mode(if metadata.readonly { mode & !0o222 } else { mode | 0o200 });
+    }
+    if permissions != original {
+        fs::set_permissions(path, permissions).with_context(context)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn file(mode: Option<u32>, readonly: bool) -> FileMetadata {
+        FileMetadata {
+            is_dir: false,
+            mtime: 1_000_000_000,
+            mode,
+            readonly,
+            hidden: false,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    fn mode_of(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn parses_preserve_lists() {
+        assert_eq!("".parse::<Preserve>().unwrap(), Preserve::default());
+        assert_eq!(
+            " times, mode ,".parse::<Preserve>().unwrap(),
+            Preserve { times: true, mode: true, ..Preserve::default() }
+        );
+        assert_eq!(
+            "attributes".parse::<Preserve>().unwrap(),
+            "attrs".parse::<Preserve>().unwrap()
+        );
+        let all = "all".parse::<Preserve>().unwrap();
+        assert!(all.times && all.mode && all.attributes && all.owner);
+        assert!("times,acl".parse::<Preserve>().is_err());
+    }
+
+    #[test]
+    fn unix_script_quotes_awkward_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("it's $(touch pwned) `x`.txt");
+        fs::write(&path, "").unwrap();
+        let preserve = Preserve { times: true, mode: true, ..Preserve::default() };
+
+        let script = unix_metadata_script(path.to_str().unwrap(), &file(Some(0o640), false), &preserve);
+        let status = Command::new("sh").arg("-ec").arg(&script).current_dir(dir.path()).status().unwrap();
+        assert!(status.success(), "{}", script);
+        assert_eq!(mode_of(&path), 0o640);
+        assert_eq!(FileMetadata::local(&path).unwrap().mtime, 1_000_000_000);
+        assert!(!dir.path().join("pwned").exists());
+    }
+
+    #[test]
+    fn unix_script_changes_owner_before_mode() {
+        let metadata = FileMetadata { uid: Some(1000), gid: Some(100), ..file(Some(0o4755), false) };
+        let script = unix_metadata_script("/srv/a", &metadata, &Preserve { mode: true, owner: true, ..Preserve::default() });
+        assert_eq!(script, "chown 1000:100 -- '/srv/a'\nchmod 4755 -- '/srv/a'");
+
+        let readonly = unix_metadata_script("/srv/a", &file(None, true), &Preserve { attributes: true, ..Preserve::default() });
+        assert_eq!(readonly, "chmod a-w -- '/srv/a'");
+    }
+
+    #[test]
+    fn windows_script_quotes_paths_and_sets_attributes() {
+        let attributes = Preserve { attributes: true, ..Preserve::default() };
+        let metadata = FileMetadata { hidden: true, ..file(None, true) };
+
+        let script = windows_metadata_script("C:\\it's [1].txt", &metadata, &attributes);
+        assert!(script.starts_with("$i = Get-Item -LiteralPath 'C:\\it''s [1].txt' -Force"), "{}", script);
+        assert!(script.contains("-bor 3;"), "{}", script);
+        assert!(!script.contains("LastWriteTimeUtc"), "{}", script);
+
+        let folder = FileMetadata { is_dir: true, ..metadata };
+        assert!(windows_metadata_script("C:\\d", &folder, &attributes).contains("-bor 2;"));
+        assert_eq!(windows_metadata_script("C:\\d", &metadata, &Preserve { mode: true, ..Preserve::default() }), "");
+    }
+
+    #[test]
+    fn windows_read_only_maps_to_the_write_bits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o664)).unwrap();
+        let attributes = Preserve { attributes: true, ..Preserve::default() };
+
+        apply_local_metadata(&path, &file(None, true), &attributes).unwrap();
+        assert_eq!(mode_of(&path), 0o444);
+        apply_local_metadata(&path, &file(None, false), &attributes).unwrap();
+        assert_eq!(mode_of(&path), 0o644);
+
+        // Linux guests carry read-only in their mode, which attributes alone leave be
+        apply_local_metadata(&path, &file(Some(0o400), true), &attributes).unwrap();
+        assert_eq!(mode_of(&path), 0o644);
+
+        let folder = dir.path().join("d");
+        fs::create_dir(&folder).unwrap();
+        fs::set_permissions(&folder, fs::Permissions::from_mode(0o755)).unwrap();
+        apply_local_metadata(&folder, &FileMetadata { is_dir: true, ..file(None, true) }, &attributes).unwrap();
+        assert_eq!(mode_of(&folder), 0o755);
+    }
+}
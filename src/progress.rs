@@ -0,0 +1,156 @@
+use crate::colors::Tags;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use qemu_agent::{EventSink, TransferEvent};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Renders transfer events as indicatif progress bars: one bar per active
+/// file, plus a file-count bar while a folder transfer runs
+#[derive(Clone, Default)]
+pub struct ProgressRenderer {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    multi: MultiProgress,
+    /// Set once a folder transfer announced its files; counts them off
+    folder: Option<ProgressBar>,
+    /// Active files by event path; parallel transfers interleave their events
+    bars: HashMap<String, ProgressBar>,
+    /// Every file that completed, for the summary after an interrupt
+    finished: Vec<String>,
+}
+
+impl ProgressRenderer {
+    /// Clears any bar still on screen
+    pub fn finish(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.clear();
+        }
+    }
+
+    /// Files that completed so far
+    pub fn finished(&self) -> Vec<String> {
+        self.state.lock().map(|state| state.finished.clone()).unwrap_or_default()
+    }
+
+    fn bytes_bar(len: Option<u64>) -> ProgressBar {
+        match len {
+            Some(len) => {
+                let bar = ProgressBar::new(len);
+                bar.set_style(
+                    ProgressStyle::default_bar()
+                        .template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} {msg}")
+                        .unwrap()
+                        .progress_chars("=>-"),
+                );
+                bar
+            }
+            None => {
+                let bar = ProgressBar::new_spinner();
+                bar.set_style(
+                    ProgressStyle::default_spinner()
+                        .template("[{elapsed_precise}] {spinner} {bytes} {msg}")
+                        .unwrap(),
+                );
+                bar
+            }
+        }
+    }
+
+    fn files_bar(files: usize) -> ProgressBar {
+        let bar = ProgressBar::new(files as u64);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] {bar:40.green/blue} {pos}/{len} files {msg}")
+                .unwrap()
+                .progress_chars("=>-"),
+        );
+        bar
+    }
+}
+
+impl State {
+    /// Prints above the bars; `println` on a bar drops output when stdout is not a terminal
+    fn println(&self, line: &str) {
+        self.multi.suspend(|| println!("{}", line));
+    }
+
+    fn clear(&mut self) {
+        for (_, bar) in self.bars.drain() {
+            bar.finish_and_clear();
+        }
+        if let Some(bar) = self.folder.take() {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+impl EventSink for ProgressRenderer {
+    fn event(&self, event: &TransferEvent) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+
+        match event {
+            TransferEvent::FolderScanned { files, bytes } => {
+                let bar = state.multi.add(Self::files_bar(*files));
+                if let Some(bytes) = bytes {
+                    bar.set_message(format!("({})", HumanBytes(*bytes)));
+                }
+                state.folder = Some(bar);
+            }
+            TransferEvent::FolderFinished { .. } => state.clear(),
+            TransferEvent::FileStarted { path, size } => {
+                let bar = state.multi.add(Self::bytes_bar(*size));
+                bar.set_message(path.clone());
+                if let Some(old) = state.bars.insert(path.clone(), bar) {
+                    old.finish_and_clear();
+                }
+            }
+            TransferEvent::BytesTransferred { path, bytes } => {
+                if let Some(bar) = state.bars.get(path) {
+                    bar.inc(*bytes);
+                }
+            }
+            TransferEvent::FileFinished { path } => {
+                if let Some(bar) = state.bars.remove(path) {
+                    bar.finish_and_clear();
+                    state.multi.remove(&bar);
+                }
+                if let Some(folder) = &state.folder {
+                    folder.inc(1);
+                }
+                state.finished.push(path.clone());
+            }
+            TransferEvent::Skipped { path, reason } => {
+                state.println(&format!("{} {}: {}", Tags::skip(), path, reason));
+                if let Some(folder) = &state.folder {
+                    folder.inc(1);
+                }
+            }
+            TransferEvent::Mismatch { path, local, guest } => {
+                state.println(&format!(
+                    "{} {}: checksum mismatch (local {}, guest {})",
+                    Tags::warn(),
+                    path,
+                    local,
+                    guest
+                ));
+            }
+            // The failing operation returns the error too and main reports it
+            TransferEvent::Error { path, .. } => {
+                if let Some(bar) = state.bars.remove(path) {
+                    // Within a folder the file is skipped or reported at the end; don't leave its bar behind
+                    if state.folder.is_some() {
+                        bar.finish_and_clear();
+                        state.multi.remove(&bar);
+                    } else {
+                        bar.abandon();
+                    }
+                }
+            }
+        }
+    }
+}
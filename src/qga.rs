@@ -1,8 +1,16 @@
+use crate::cancel::CancelToken;
+use crate::capabilities::Capabilities;
+use crate::chunking::{ChunkSize, MAX_CHUNK_SIZE};
+use crate::events::{EventSink, NullSink, TransferEvent};
+use crate::error::{QgaError, QgaResult};
+use crate::transport::{Transport, VirshOptions, VirshTransport};
 use anyhow::{Context, Result};
 use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::process::Command;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct QemuCommand {
@@ -11,9 +19,21 @@ struct QemuCommand {
 }
 
 #[derive(Deserialize, Debug)]
-struct QemuResponse {
-    #[serde(rename = "return")]
-    return_value: serde_json::Value,
+#[serde(untagged)]
+enum QemuResponse {
+    Return {
+        #[serde(rename = "return")]
+        return_value: serde_json::Value,
+    },
+    Error {
+        error: QemuErrorReply,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct QemuErrorReply {
+    class: String,
+    desc: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -26,39 +46,181 @@ pub struct ExecStatus {
     pub err_data: Option<String>,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct GuestInfo {
+    pub version: String,
+    pub supported_commands: Vec<SupportedCommand>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SupportedCommand {
+    pub name: String,
+    pub enabled: bool,
+}
+
+/// Deadlines applied to agent operations
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Round trip of a single agent command
+    pub command: Duration,
+    /// How long a guest process started through `guest-exec` may run
+    pub exec: Duration,
+    /// The `guest-ping` liveness check
+    pub ping: Duration,
+    /// The guest's `tar` packing or unpacking a folder in archive mode
+    pub archive: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            command: Duration::from_secs(30),
+            exec: Duration::from_secs(60),
+            ping: Duration::from_secs(5),
+            archive: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
 pub struct QemuGuestAgent {
     pub vm_name: String,
+    pub timeouts: Timeouts,
+    pub chunk_size: ChunkSize,
+    transport: Box<dyn Transport>,
+    pub(crate) capabilities: OnceLock<Capabilities>,
+    events: Arc<dyn EventSink>,
+    cancel: CancelToken,
 }
 
 impl QemuGuestAgent {
-    pub fn new(vm_name: String) -> Self {
-        Self { vm_name }
+    pub fn new(vm_name: String, options: VirshOptions) -> Self {
+        let transport = Box::new(VirshTransport::new(vm_name.clone(), options));
+        Self::with_transport(vm_name, transport)
+    }
+
+    pub fn with_transport(vm_name: String, transport: Box<dyn Transport>) -> Self {
+        Self {
+            vm_name,
+            timeouts: Timeouts::default(),
+            chunk_size: ChunkSize::default(),
+            transport,
+            capabilities: OnceLock::new(),
+            events: Arc::new(NullSink),
+            cancel: CancelToken::default(),
+        }
+    }
+
+    /// Routes transfer progress to `sink` instead of discarding it
+    pub fn set_event_sink(&mut self, sink: impl EventSink + 'static) {
+        self.events = Arc::new(sink);
+    }
+
+    /// Token that stops transfers, folder walks and exec waits when cancelled
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Fails with [`QgaError::Cancelled`] once the agent's token is cancelled
+    pub fn check_cancelled(&self) -> QgaResult<()> {
+        self.cancel.check()
+    }
+
+    /// Largest chunk a single `guest-file-write` can carry over this transport
+    pub fn write_chunk_limit(&self) -> usize {
+        match self.transport.max_command_len() {
+            // Leave room for the JSON envelope, then undo the base64 expansion
+            Some(len) => (len.saturating_sub(256) / 4 * 3).min(MAX_CHUNK_SIZE),
+            None => MAX_CHUNK_SIZE,
+        }
+    }
+
+    pub(crate) fn emit(&self, event: TransferEvent) {
+        self.events.event(&event);
+    }
+
+    pub fn run_command(&self, command: &str, arguments: serde_json::Value) -> QgaResult<serde_json::Value> {
+        self.run_command_with_timeout(command, arguments, self.timeouts.command)
     }
 
-    pub fn run_command(&self, command: &str, arguments: serde_json::Value) -> Result<serde_json::Value> {
+    pub fn run_command_with_timeout(
+        &self,
+        command: &str,
+        arguments: serde_json::Value,
+        timeout: Duration,
+    ) -> QgaResult<serde_json::Value> {
         let cmd = QemuCommand {
             execute: command.to_string(),
             arguments,
         };
 
         let json_cmd = serde_json::to_string(&cmd)?;
+        let output = match self.transport.send(&json_cmd, timeout) {
+            Ok(output) => output,
+            // Ctrl+C reaches a virsh child too; report the interrupt rather than its death
+            Err(_) if self.cancel.is_cancelled() => return Err(QgaError::Cancelled),
+            Err(err) => return Err(err),
+        };
 
-        let output = Command::new("sudo")
-            .args(&["virsh", "qemu-agent-command", &self.vm_name, &json_cmd])
-            .output()
-            .context("Failed to execute virsh command")?;
+        match serde_json::from_str(&output)? {
+            QemuResponse::Return { return_value } => Ok(return_value),
+            QemuResponse::Error { error } => Err(QgaError::from_guest(command, &error.class, &error.desc)),
+        }
+    }
 
-        if !output.status.success() {
-            anyhow::bail!(
-                "Command failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+    /// Checks that the agent inside the guest answers, failing fast when the channel is dead
+    pub fn ping(&self) -> QgaResult<()> {
+        match self.run_command_with_timeout("guest-ping", json!({}), self.timeouts.ping) {
+            Ok(_) => Ok(()),
+            // A chardev socket with no agent behind it accepts commands and never answers
+            Err(QgaError::Timeout { .. }) => Err(QgaError::AgentNotConnected),
+            Err(err) => Err(err),
         }
+    }
+
+    pub fn guest_info(&self) -> Result<GuestInfo> {
+        let result = self.run_command("guest-info", json!({}))?;
+        serde_json::from_value(result).context("Failed to parse guest info")
+    }
+
+    /// Polls `guest-ping` until the agent answers or `timeout` elapses. With
+    /// `query_info` or `required` commands, `guest-info` must answer too and
+    /// every required command must be enabled.
+    pub fn wait_until_ready(
+        &self,
+        timeout: Duration,
+        query_info: bool,
+        required: &[String],
+    ) -> Result<Option<GuestInfo>> {
+        // A timeout too large to represent means waiting for good
+        let deadline = Instant::now().checked_add(timeout);
+        let remaining = || deadline.map_or(Duration::MAX, |d| d.saturating_duration_since(Instant::now()));
+
+        loop {
+            let attempt = self.timeouts.ping.min(remaining()).max(Duration::from_millis(100));
 
-        let response: QemuResponse = serde_json::from_slice(&output.stdout)
-            .context("Failed to parse QEMU response")?;
+            if self.run_command_with_timeout("guest-ping", json!({}), attempt).is_ok() {
+                if !query_info && required.is_empty() {
+                    return Ok(None);
+                }
 
-        Ok(response.return_value)
+                if let Ok(info) = self.guest_info() {
+                    let capabilities = Capabilities::from_info(&info);
+                    for name in required {
+                        capabilities.check(name)?;
+                    }
+                    return Ok(Some(info));
+                }
+            }
+
+            self.check_cancelled()?;
+            if remaining().is_zero() {
+                return Err(QgaError::Timeout {
+                    command: "guest-ping".to_string(),
+                }
+                .into());
+            }
+            thread::sleep(Duration::from_secs(1).min(remaining()));
+        }
     }
 
     pub fn guest_file_open(&self, path: &str, mode: &str) -> Result<i64> {
@@ -116,6 +278,27 @@ impl QemuGuestAgent {
         Ok((data, eof))
     }
 
+    /// Moves the file position; `whence` is "set", "cur" or "end". Returns the new position.
+    pub fn guest_file_seek(&self, handle: i64, offset: i64, whence: &str) -> Result<u64> {
+        let result = self.run_command(
+            "guest-file-seek",
+            json!({
+                "handle": handle,
+                "offset": offset,
+                "whence": whence
+            }),
+        )?;
+
+        result["position"]
+            .as_u64()
+            .context("Failed to get file position")
+    }
+
+    pub fn guest_file_flush(&self, handle: i64) -> Result<()> {
+        self.run_command("guest-file-flush", json!({ "handle": handle }))?;
+        Ok(())
+    }
+
     pub fn guest_exec(&self, path: &str, args: Option<Vec<String>>, capture_output: bool) -> Result<i64> {
         let mut arguments = json!({ "path": path });
 
@@ -146,6 +329,22 @@ impl QemuGuestAgent {
     pub fn normalize_windows_path(path: &str) -> String {
         path.replace('/', "\\").replace('\\', "\\\\")
     }
-}
 
-pub use serde_json::json;
+    /// A guest path in the form `guest-file-open` expects on the guest's platform
+    pub fn guest_file_path(path: &str) -> String {
+        if Self::is_windows_path(path) {
+            Self::normalize_windows_path(path)
+        } else {
+            path.to_string()
+        }
+    }
+
+    /// Appends a `/`-separated relative path to a guest folder, using the guest's separator
+    pub fn join_guest_path(folder: &str, rel: &str) -> String {
+        if Self::is_windows_path(folder) {
+            format!("{}\\{}", folder.trim_end_matches('\\'), rel.replace('/', "\\"))
+        } else {
+            format!("{}/{}", folder.trim_end_matches('/'), rel)
+        }
+    }
+}
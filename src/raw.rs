@@ -0,0 +1,75 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+/// Parses one raw command line. Accepts either a complete QGA request
+/// (`{"execute": "guest-info"}`) or a command name followed by optional JSON
+/// arguments (`guest-file-open {"path": "/etc/hostname"}`).
+pub fn parse_raw_command(line: &str) -> Result<(String, Value)> {
+    let line = line.trim();
+
+    if line.starts_with('{') {
+        let request: Value = serde_json::from_str(line).context("Invalid JSON request")?;
+        let command = request["execute"]
+            .as_str()
+            .context("Request has no \"execute\" member")?
+            .to_string();
+        let arguments = request.get("arguments").cloned().unwrap_or_else(|| json!({}));
+        return Ok((command, arguments));
+    }
+
+    let (command, arguments) = match line.split_once(char::is_whitespace) {
+        Some((command, rest)) => (command, rest.trim()),
+        None => (line, ""),
+    };
+    parse_raw_arguments(command, arguments)
+}
+
+/// Builds a command from a name and its JSON arguments, which may be empty
+pub fn parse_raw_arguments(command: &str, arguments: &str) -> Result<(String, Value)> {
+    let arguments = if arguments.trim().is_empty() {
+        json!({})
+    } else {
+        serde_json::from_str(arguments)
+            .with_context(|| format!("Invalid JSON arguments for {}", command))?
+    };
+
+    if !arguments.is_object() {
+        anyhow::bail!("Arguments for {} must be a JSON object", command);
+    }
+
+    Ok((command.to_string(), arguments))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_full_requests() {
+        let (command, arguments) =
+            parse_raw_command(r#"{"execute": "guest-file-open", "arguments": {"path": "/etc/hostname"}}"#).unwrap();
+        assert_eq!(command, "guest-file-open");
+        assert_eq!(arguments, json!({ "path": "/etc/hostname" }));
+
+        let (command, arguments) = parse_raw_command(r#" {"execute": "guest-info"} "#).unwrap();
+        assert_eq!(command, "guest-info");
+        assert_eq!(arguments, json!({}));
+    }
+
+    #[test]
+    fn parses_name_and_arguments() {
+        let (command, arguments) = parse_raw_command(r#"guest-exec {"path": "ls", "arg": ["-l"]}"#).unwrap();
+        assert_eq!(command, "guest-exec");
+        assert_eq!(arguments, json!({ "path": "ls", "arg": ["-l"] }));
+
+        assert_eq!(parse_raw_command("guest-ping").unwrap(), ("guest-ping".to_string(), json!({})));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse_raw_command(r#"{"arguments": {}}"#).is_err());
+        assert!(parse_raw_command("{not json").is_err());
+        assert!(parse_raw_command("guest-ping [1, 2]").is_err());
+        assert!(parse_raw_command("guest-ping {oops}").is_err());
+    }
+}
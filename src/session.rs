@@ -0,0 +1,262 @@
+use crate::error::{QgaError, QgaResult};
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Sentinel byte the agent emits before a `guest-sync-delimited` reply. Sending it
+/// to the agent also resets its JSON parser.
+const SYNC_DELIMITER: u8 = 0xFF;
+
+/// Stale replies tolerated before giving up on a single command
+const MAX_STALE_REPLIES: usize = 64;
+
+/// A byte stream whose blocking reads and writes can be bounded
+pub(crate) trait Channel: Read + Write {
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Channel for UnixStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
+impl Channel for TcpStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
+/// A persistent connection to the guest agent. The `guest-sync-delimited`
+/// handshake runs once on first use and again whenever the stream may hold
+/// leftovers from an interrupted exchange.
+pub(crate) struct Session<S: Channel> {
+    stream: BufReader<S>,
+    synced: bool,
+    ids: RandomState,
+    counter: u64,
+}
+
+impl<S: Channel> Session<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            synced: false,
+            ids: RandomState::new(),
+            counter: 0,
+        }
+    }
+
+    /// Sends one serialized command and returns the matching raw JSON reply. A
+    /// timed out exchange leaves the connection usable; the next call resyncs.
+    pub fn execute(&mut self, command: &str, timeout: Duration) -> QgaResult<String> {
+        let mut request: Value = serde_json::from_str(command)?;
+        let name = request["execute"].as_str().unwrap_or_default().to_string();
+
+        let result = self.exchange(&mut request, timeout);
+        result.map_err(|err| {
+            self.synced = false;
+            let timed_out = err
+                .chain()
+                .filter_map(|e| e.downcast_ref::<io::Error>())
+                .any(|e| matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+            if timed_out {
+                QgaError::Timeout { command: name }
+            } else {
+                QgaError::Transport(format!("{:#}", err))
+            }
+        })
+    }
+
+    fn exchange(&mut self, request: &mut Value, timeout: Duration) -> Result<String> {
+        self.stream.get_ref().set_timeout(Some(timeout))?;
+
+        if !self.synced {
+            self.sync()?;
+        }
+
+        let id = self.next_id();
+        request["id"] = json!(id);
+
+        self.write_line(&request.to_string())?;
+        self.read_reply(id)
+    }
+
+    /// Flushes stale output with `guest-sync-delimited` using a fresh random id
+    pub fn sync(&mut self) -> Result<()> {
+        let id = self.next_id();
+        let request = json!({ "execute": "guest-sync-delimited", "arguments": { "id": id } });
+
+        let writer = self.stream.get_mut();
+        writer.write_all(&[SYNC_DELIMITER])?;
+        writer.write_all(request.to_string().as_bytes())?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+
+        for _ in 0..MAX_STALE_REPLIES {
+            let mut discarded = Vec::new();
+            if self.stream.read_until(SYNC_DELIMITER, &mut discarded)? == 0 {
+                anyhow::bail!("Guest agent closed the connection during sync");
+            }
+            if discarded.last() != Some(&SYNC_DELIMITER) {
+                continue;
+            }
+
+            let reply = self.read_line()?;
+            if serde_json::from_str::<Value>(&reply)
+                .map(|v| v["return"].as_u64() == Some(id))
+                .unwrap_or(false)
+            {
+                self.synced = true;
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Guest agent did not acknowledge guest-sync-delimited")
+    }
+
+    fn read_reply(&mut self, id: u64) -> Result<String> {
+        // Replies to earlier, abandoned commands may still be queued ahead of ours.
+        // Those carry a different id, or none when they answer the handshake of
+        // an earlier connection, and are dropped until ours turns up.
+        for _ in 0..MAX_STALE_REPLIES {
+            let line = self.read_line()?;
+            let reply: Value = match serde_json::from_str(&line) {
+                Ok(reply) => reply,
+                Err(_) => continue,
+            };
+
+            if reply.get("id").and_then(Value::as_u64) == Some(id) {
+                return Ok(line);
+            }
+        }
+
+        anyhow::bail!("Guest agent stream out of sync: no reply matching id {}", id)
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        loop {
+            let mut line = Vec::new();
+            if self.stream.read_until(b'\n', &mut line)? == 0 {
+                anyhow::bail!("Guest agent closed the connection");
+            }
+            line.retain(|&b| b != SYNC_DELIMITER);
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                return Ok(line.into_owned());
+            }
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let writer = self.stream.get_mut();
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.counter += 1;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        // Keep ids within JSON's exact integer range
+        self.ids.hash_one((self.counter, nanos)) & ((1 << 53) - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Reads the next request the session sent, dropping sync delimiters
+    fn next_request(agent: &mut BufReader<UnixStream>) -> Value {
+        let mut line = Vec::new();
+        agent.read_until(b'\n', &mut line).unwrap();
+        line.retain(|&b| b != SYNC_DELIMITER);
+        serde_json::from_slice(&line).unwrap()
+    }
+
+    /// Answers a `guest-sync-delimited` after some leftover output, as an agent
+    /// with a stale reply still queued would
+    fn answer_sync(agent: &mut BufReader<UnixStream>) {
+        let request = next_request(agent);
+        assert_eq!(request["execute"], "guest-sync-delimited");
+        let id = request["arguments"]["id"].as_u64().unwrap();
+
+        let writer = agent.get_mut();
+        writer.write_all(b"{\"return\": \"left over\"}\n").unwrap();
+        writer.write_all(&[SYNC_DELIMITER]).unwrap();
+        writer.write_all(format!("{{\"return\": {}}}\n", id).as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn syncs_before_the_first_command_and_skips_stale_replies() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let agent = thread::spawn(move || {
+            let mut agent = BufReader::new(server);
+            answer_sync(&mut agent);
+
+            let request = next_request(&mut agent);
+            assert_eq!(request["execute"], "guest-ping");
+            let id = request["id"].as_u64().unwrap();
+            let writer = agent.get_mut();
+            writer.write_all(format!("{{\"return\": \"stale\", \"id\": {}}}\n", id ^ 1).as_bytes()).unwrap();
+            writer.write_all(b"not json\n").unwrap();
+            writer.write_all(b"{\"return\": \"no id\"}\n").unwrap();
+            writer.write_all(format!("{{\"return\": \"fresh\", \"id\": {}}}\n", id).as_bytes()).unwrap();
+        });
+
+        let mut session = Session::new(client);
+        let reply = session.execute(r#"{"execute": "guest-ping"}"#, Duration::from_secs(5)).unwrap();
+        let reply: Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["return"], "fresh");
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn resyncs_after_a_timeout() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let agent = thread::spawn(move || {
+            let mut agent = BufReader::new(server);
+            answer_sync(&mut agent);
+            // Never answer the first command
+            assert_eq!(next_request(&mut agent)["execute"], "guest-info");
+
+            answer_sync(&mut agent);
+            let request = next_request(&mut agent);
+            assert_eq!(request["execute"], "guest-ping");
+            let id = request["id"].as_u64().unwrap();
+            writeln!(agent.get_mut(), "{{\"return\": {{}}, \"id\": {}}}", id).unwrap();
+        });
+
+        let mut session = Session::new(client);
+        let err = session
+            .execute(r#"{"execute": "guest-info"}"#, Duration::from_millis(200))
+            .unwrap_err();
+        assert!(matches!(err, QgaError::Timeout { ref command } if command == "guest-info"), "{:?}", err);
+
+        session.execute(r#"{"execute": "guest-ping"}"#, Duration::from_secs(5)).unwrap();
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn closed_connection_is_a_transport_error() {
+        let (client, server) = UnixStream::pair().unwrap();
+        drop(server);
+
+        let mut session = Session::new(client);
+        let err = session.execute(r#"{"execute": "guest-ping"}"#, Duration::from_secs(5)).unwrap_err();
+        assert!(matches!(err, QgaError::Transport(_)), "{:?}", err);
+    }
+}
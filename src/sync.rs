@@ -0,0 +1,573 @@
+use crate::checksum::{sha256_file, ChecksumMismatch};
+use crate::events::TransferEvent;
+use crate::filter::{rel_key, PathFilter};
+use crate::listing::{local_entry_path, GuestEntry};
+use crate::metadata::Preserve;
+use crate::qga::QemuGuestAgent;
+use crate::transfer::{done_jobs, FileJob, FileOutcome, TransferOptions};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// How `sync_to_guest` and `sync_from_guest` decide what to transfer
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Compare SHA-256 of same-sized files instead of modification times
+    pub checksum: bool,
+    /// Remove destination files and folders that do not exist at the source
+    pub delete: bool,
+    pub transfer: TransferOptions,
+}
+
+/// Outcome of a sync; paths are relative to the synced folders
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub transferred: Vec<PathBuf>,
+    /// Files already up to date
+    pub unchanged: usize,
+    pub deleted: Vec<PathBuf>,
+    /// Files the guest refused, with the reason
+    pub skipped: Vec<(PathBuf, String)>,
+    pub mismatched: Vec<ChecksumMismatch>,
+}
+
+/// A file or folder on either side, keyed by its `/`-separated relative path
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    is_dir: bool,
+    size: u64,
+    mtime: i64,
+}
+
+impl From<&GuestEntry> for Entry {
+    fn from(entry: &GuestEntry) -> Self {
+        Self {
+            is_dir: entry.is_dir,
+            size: entry.size,
+            mtime: entry.mtime,
+        }
+    }
+}
+
+impl QemuGuestAgent {
+    /// Makes `guest_folder` match `local_folder`, sending only files that are
+    /// missing or differ in size or modification time (or hash differently,
+    /// with `checksum`)
+    pub fn sync_to_guest(&self, local_folder: &Path, guest_folder: &str, options: &SyncOptions) -> Result<SyncReport> {
+        self.create_directory(guest_folder)?;
+
+        let mut filter = PathFilter::new(&options.transfer)?;
+        let local = list_local(local_folder, &mut filter)?;
+        filter.freeze_ignore_files();
+        let guest: BTreeMap<String, Entry> = self
+            .filter_listing(guest_folder, self.list_tree(guest_folder)?, &mut filter)?
+            .entries
+            .iter()
+            .map(|e| (e.path.clone(), Entry::from(e)))
+            .collect();
+
+        let mut report = SyncReport::default();
+        let mut jobs = Vec::new();
+        let mut bytes = 0;
+
+        for (rel, entry) in &local {
+            self.check_cancelled()?;
+            let guest_path = Self::join_guest_path(guest_folder, rel);
+            let existing = guest.get(rel);
+
+            if entry.is_dir {
+                if !existing.is_some_and(|e| e.is_dir) {
+                    if existing.is_some() {
+                        self.remove_tree(&guest_path)?;
+                    }
+                    self.create_directory(&guest_path)?;
+                }
+                continue;
+            }
+
+            let local_path = local_folder.join(rel);
+            let changed = match existing {
+                None => true,
+                Some(existing) if existing.is_dir => {
+                    self.remove_tree(&guest_path)?;
+                    true
+                }
+                Some(existing) => self.file_changed(entry, existing, &local_path, &guest_path, options)?,
+            };
+
+            if changed {
+                bytes += entry.size;
+                jobs.push(FileJob {
+                    local: local_path,
+                    guest: guest_path,
+                    rel: PathBuf::from(rel),
+                });
+            } else {
+                report.unchanged += 1;
+            }
+        }
+
+        self.emit(TransferEvent::FolderScanned { files: jobs.len(), bytes: Some(bytes) });
+        let per_file = options.transfer.per_file();
+        let outcomes = self.run_jobs(&jobs, options.transfer.jobs, |job| {
+            let outcome = FileOutcome::from(self.upload_file_with(&job.local, &job.guest, &per_file));
+            if let FileOutcome::Skipped(reason) = &outcome {
+                self.emit(TransferEvent::Skipped { path: job.local.display().to_string(), reason: reason.clone() });
+            }
+            outcome
+        });
+        let done = done_jobs(&jobs, &outcomes);
+        self.finish_sync(&jobs, outcomes, &mut report)?;
+
+        let preserve = sync_preserve(options);
+        if preserve.any() {
+            // Folders change whenever their contents do, so they are always brought back in line
+            let dirs: Vec<FileJob> = local
+                .iter()
+                .filter(|(_, entry)| entry.is_dir)
+                .map(|(rel, _)| FileJob {
+                    local: local_folder.join(rel),
+                    guest: Self::join_guest_path(guest_folder, rel),
+                    rel: PathBuf::from(rel),
+                })
+                .collect();
+            self.preserve_uploaded_folder(local_folder, guest_folder, &done, &dirs, &preserve)?;
+        }
+
+        if options.delete {
+            let (files, dirs) = extraneous(&guest, &local);
+            for rel in files {
+                self.check_cancelled()?;
+                self.remove_file(&Self::join_guest_path(guest_folder, &rel))?;
+                report.deleted.push(PathBuf::from(rel));
+            }
+            for rel in dirs {
+                self.check_cancelled()?;
+                if self.remove_empty_directory(&Self::join_guest_path(guest_folder, &rel))? {
+                    report.deleted.push(PathBuf::from(rel));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Makes `local_folder` match `guest_folder`, fetching only files that are
+    /// missing or differ in size or modification time (or hash differently,
+    /// with `checksum`)
+    pub fn sync_from_guest(&self, guest_folder: &str, local_folder: &Path, options: &SyncOptions) -> Result<SyncReport> {
+        let mut filter = PathFilter::new(&options.transfer)?;
+        let tree = self.filter_listing(guest_folder, self.list_tree(guest_folder)?, &mut filter)?;
+        let guest = tree.entries;
+        filter.freeze_ignore_files();
+        fs::create_dir_all(local_folder)?;
+        let local = list_local(local_folder, &mut filter)?;
+
+        let mut report = SyncReport::default();
+        let mut jobs = Vec::new();
+        let mut bytes = 0;
+
+        for entry in &guest {
+            self.check_cancelled()?;
+            let local_path = local_entry_path(local_folder, &entry.path)?;
+            let existing = local.get(&entry.path);
+
+            if existing.is_some_and(|e| e.is_dir != entry.is_dir) {
+                remove_local(&local_path)?;
+            }
+            let existing = existing.filter(|e| e.is_dir == entry.is_dir);
+
+            if entry.is_dir {
+                fs::create_dir_all(&local_path)?;
+                continue;
+            }
+
+            let guest_path = Self::join_guest_path(guest_folder, &entry.path);
+            let changed = match existing {
+                None => true,
+                Some(existing) => self.file_changed(&Entry::from(entry), existing, &local_path, &guest_path, options)?,
+            };
+
+            if changed {
+                bytes += entry.size;
+                jobs.push(FileJob {
+                    local: local_path,
+                    guest: guest_path,
+                    rel: PathBuf::from(&entry.path),
+                });
+            } else {
+                report.unchanged += 1;
+            }
+        }
+
+        self.emit(TransferEvent::FolderScanned { files: jobs.len(), bytes: Some(bytes) });
+        let per_file = options.transfer.per_file();
+        let outcomes = self.run_jobs(&jobs, options.transfer.jobs, |job| {
+            let outcome = FileOutcome::from(self.download_file_with(&job.guest, &job.local, &per_file));
+            if let FileOutcome::Skipped(reason) = &outcome {
+                self.emit(TransferEvent::Skipped { path: job.guest.clone(), reason: reason.clone() });
+            }
+            outcome
+        });
+        let done: HashSet<&Path> = done_jobs(&jobs, &outcomes).iter().map(|job| job.rel.as_path()).collect();
+        self.finish_sync(&jobs, outcomes, &mut report)?;
+        report.skipped.extend(tree.skipped.into_iter().map(|(rel, reason)| (PathBuf::from(rel), reason)));
+
+        let preserve = sync_preserve(options);
+        if preserve.any() {
+            let (files, dirs): (Vec<&GuestEntry>, Vec<&GuestEntry>) = guest
+                .iter()
+                .filter(|e| e.is_dir || done.contains(&Path::new(&e.path)))
+                .partition(|e| !e.is_dir);
+            self.preserve_downloaded_folder(guest_folder, local_folder, &files, &dirs, &preserve)?;
+        }
+
+        if options.delete {
+            let guest: BTreeMap<String, Entry> = guest.iter().map(|e| (e.path.clone(), Entry::from(e))).collect();
+            let (files, dirs) = extraneous(&local, &guest);
+            for rel in files {
+                self.check_cancelled()?;
+                fs::remove_file(local_folder.join(&rel))?;
+                report.deleted.push(PathBuf::from(rel));
+            }
+            for rel in dirs {
+                self.check_cancelled()?;
+                if remove_empty_local_dir(&local_folder.join(&rel))? {
+                    report.deleted.push(PathBuf::from(rel));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Whether the destination copy of a file differs from its source. Times are
+    /// compared for equality rather than order, since the host and guest clocks
+    /// need not agree and a destination edited later is still out of date.
+    fn file_changed(
+        &self,
+        source: &Entry,
+        destination: &Entry,
+        local_path: &Path,
+        guest_path: &str,
+        options: &SyncOptions,
+    ) -> Result<bool> {
+        if source.size != destination.size {
+            return Ok(true);
+        }
+        if options.checksum {
+            return Ok(sha256_file(local_path)? != self.guest_sha256(guest_path)?);
+        }
+        Ok(source.mtime != destination.mtime)
+    }
+
+    fn finish_sync(&self, jobs: &[FileJob], outcomes: Vec<Option<FileOutcome>>, report: &mut SyncReport) -> Result<()> {
+        report.transferred = jobs
+            .iter()
+            .zip(&outcomes)
+            .filter(|(_, outcome)| matches!(outcome, Some(FileOutcome::Done)))
+            .map(|(job, _)| job.rel.clone())
+            .collect();
+
+        let folder = self.collect_outcomes(jobs, outcomes)?;
+        report.skipped = folder.skipped;
+        report.mismatched = folder.mismatched;
+
+        self.emit(TransferEvent::FolderFinished { files: folder.files });
+        Ok(())
+    }
+}
+
+/// Metadata a sync carries over: what was asked for, plus modification times
+/// unless files are compared by checksum, so that transferred files compare
+/// equal to their source next time
+fn sync_preserve(options: &SyncOptions) -> Preserve {
+    Preserve {
+        times: options.transfer.preserve.times || !options.checksum,
+        ..options.transfer.preserve
+    }
+}
+
+/// Walks a local folder into the same shape as a guest listing, leaving out
+/// what `filter` rejects
+fn list_local(folder: &Path, filter: &mut PathFilter) -> Result<BTreeMap<String, Entry>> {
+    let mut entries = BTreeMap::new();
+    if !folder.exists() {
+        return Ok(entries);
+    }
+
+    for entry in filter.walk_local(folder)? {
+        let entry = entry?;
+        let file_type = entry.file_type();
+        if entry.depth() == 0 || (!file_type.is_dir() && !file_type.is_file()) {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+
+        entries.insert(
+            rel_key(entry.path().strip_prefix(folder)?),
+            Entry {
+                is_dir: file_type.is_dir(),
+                size: if file_type.is_dir() { 0 } else { metadata.len() },
+                mtime,
+            },
+        );
+    }
+
+    let files = entries.iter().filter(|(_, e)| !e.is_dir).map(|(rel, _)| rel.clone());
+    if let Some(needed) = filter.needed_dirs(files) {
+        entries.retain(|rel, e| !e.is_dir || needed.contains(rel));
+    }
+
+    Ok(entries)
+}
+
+/// Files and folders in `destination` with no counterpart in `source`, as a
+/// sync with `delete` removes them: the files one by one, then the folders
+/// deepest first and only once empty. Both listings are filtered, so what the
+/// filter leaves out at the destination is never removed, and neither is the
+/// folder holding it. Entries below a folder the sync already replaced with a
+/// file are gone and left out.
+fn extraneous(destination: &BTreeMap<String, Entry>, source: &BTreeMap<String, Entry>) -> (Vec<String>, Vec<String>) {
+    let replaced = |rel: &str| {
+        let mut path = rel;
+        while let Some((parent, _)) = path.rsplit_once('/') {
+            if source.get(parent).is_some_and(|e| !e.is_dir) {
+                return true;
+            }
+            path = parent;
+        }
+        false
+    };
+
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    // Reverse order puts every folder after its contents
+    for (rel, entry) in destination.iter().rev() {
+        if source.contains_key(rel) || replaced(rel) {
+            continue;
+        }
+        if entry.is_dir {
+            dirs.push(rel.clone());
+        } else {
+            files.push(rel.clone());
+        }
+    }
+    files.reverse();
+    (files, dirs)
+}
+
+/// Removes a local folder only if it is empty; returns whether it was removed
+fn remove_empty_local_dir(path: &Path) -> Result<bool> {
+    if fs::read_dir(path)?.next().is_some() {
+        return Ok(false);
+    }
+    fs::remove_dir(path)?;
+    Ok(true)
+}
+
+fn remove_local(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_guest::HostGuest;
+    use std::time::Duration;
+
+    /// Writes `files` below `root`, creating their folders
+    fn write_tree(root: &Path, files: &[(&str, &str)]) {
+        for (rel, contents) in files {
+            let path = root.join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    fn set_mtime(path: &Path, secs: u64) {
+        let file = fs::File::open(path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+    }
+
+    fn mtime(path: &Path) -> u64 {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        modified.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    /// A host folder and a folder standing in for the guest one
+    fn folders() -> (tempfile::TempDir, PathBuf, PathBuf, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("local");
+        let guest = dir.path().join("guest");
+        fs::create_dir_all(&local).unwrap();
+        fs::create_dir_all(&guest).unwrap();
+        let guest_path = guest.to_str().unwrap().to_string();
+        (dir, local, guest, guest_path)
+    }
+
+    #[test]
+    fn push_sends_files_whose_time_differs_either_way() {
+        let (agent, _) = HostGuest::agent();
+        let (_dir, local, guest, guest_path) = folders();
+        write_tree(&local, &[("a.txt", "one"), ("b.txt", "same")]);
+        write_tree(&guest, &[("a.txt", "two"), ("b.txt", "same")]);
+        // The guest copy is newer, as after an edit there or with its clock ahead
+        set_mtime(&local.join("a.txt"), 1_000_000);
+        set_mtime(&guest.join("a.txt"), 2_000_000);
+        set_mtime(&local.join("b.txt"), 1_500_000);
+        set_mtime(&guest.join("b.txt"), 1_500_000);
+
+        let report = agent.sync_to_guest(&local, &guest_path, &SyncOptions::default()).unwrap();
+        assert_eq!(report.transferred, vec![PathBuf::from("a.txt")]);
+        assert_eq!(report.unchanged, 1);
+        assert_eq!(fs::read_to_string(guest.join("a.txt")).unwrap(), "one");
+        assert_eq!(mtime(&guest.join("a.txt")), 1_000_000);
+
+        let report = agent.sync_to_guest(&local, &guest_path, &SyncOptions::default()).unwrap();
+        assert!(report.transferred.is_empty());
+        assert_eq!(report.unchanged, 2);
+    }
+
+    #[test]
+    fn pull_sends_files_whose_time_differs_either_way() {
+        let (agent, _) = HostGuest::agent();
+        let (_dir, local, guest, guest_path) = folders();
+        write_tree(&guest, &[("a.txt", "one")]);
+        write_tree(&local, &[("a.txt", "two")]);
+        set_mtime(&guest.join("a.txt"), 1_000_000);
+        set_mtime(&local.join("a.txt"), 2_000_000);
+
+        let report = agent.sync_from_guest(&guest_path, &local, &SyncOptions::default()).unwrap();
+        assert_eq!(report.transferred, vec![PathBuf::from("a.txt")]);
+        assert_eq!(fs::read_to_string(local.join("a.txt")).unwrap(), "one");
+        assert_eq!(mtime(&local.join("a.txt")), 1_000_000);
+
+        let report = agent.sync_from_guest(&guest_path, &local, &SyncOptions::default()).unwrap();
+        assert!(report.transferred.is_empty());
+        assert_eq!(report.unchanged, 1);
+    }
+
+    #[test]
+    fn checksum_ignores_times() {
+        let (agent, _) = HostGuest::agent();
+        let (_dir, local, guest, guest_path) = folders();
+        write_tree(&local, &[("same.txt", "data"), ("edited.txt", "new")]);
+        write_tree(&guest, &[("same.txt", "data"), ("edited.txt", "old")]);
+        set_mtime(&local.join("same.txt"), 1_000_000);
+        set_mtime(&local.join("edited.txt"), 1_000_000);
+        let options = SyncOptions { checksum: true, ..SyncOptions::default() };
+
+        let report = agent.sync_to_guest(&local, &guest_path, &options).unwrap();
+        assert_eq!(report.transferred, vec![PathBuf::from("edited.txt")]);
+        assert_eq!(report.unchanged, 1);
+    }
+
+    #[test]
+    fn push_replaces_entries_that_changed_type() {
+        let (agent, _) = HostGuest::agent();
+        let (_dir, local, guest, guest_path) = folders();
+        write_tree(&local, &[("x/inner.txt", "inner"), ("y", "now a file")]);
+        write_tree(&guest, &[("x", "was a file"), ("y/deep/z.txt", "was a folder")]);
+
+        let report = agent.sync_to_guest(&local, &guest_path, &SyncOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(guest.join("x/inner.txt")).unwrap(), "inner");
+        assert_eq!(fs::read_to_string(guest.join("y")).unwrap(), "now a file");
+        assert_eq!(report.transferred.len(), 2);
+    }
+
+    #[test]
+    fn pull_replaces_entries_that_changed_type() {
+        let (agent, _) = HostGuest::agent();
+        let (_dir, local, guest, guest_path) = folders();
+        write_tree(&guest, &[("x/inner.txt", "inner"), ("y", "now a file")]);
+        write_tree(&local, &[("x", "was a file"), ("y/deep/z.txt", "was a folder")]);
+
+        let report = agent.sync_from_guest(&guest_path, &local, &SyncOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(local.join("x/inner.txt")).unwrap(), "inner");
+        assert_eq!(fs::read_to_string(local.join("y")).unwrap(), "now a file");
+        assert_eq!(report.transferred.len(), 2);
+    }
+
+    #[test]
+    fn delete_removes_what_the_source_lacks() {
+        let (agent, _) = HostGuest::agent();
+        let (_dir, local, guest, guest_path) = folders();
+        write_tree(&local, &[("keep.txt", "keep")]);
+        write_tree(&guest, &[("keep.txt", "keep"), ("old.txt", "old")]);
+        let options = SyncOptions { delete: true, ..SyncOptions::default() };
+
+        let report = agent.sync_to_guest(&local, &guest_path, &options).unwrap();
+        assert_eq!(report.deleted, vec![PathBuf::from("old.txt")]);
+        assert!(!guest.join("old.txt").exists());
+        assert!(guest.join("keep.txt").exists());
+    }
+
+    /// Entries a filtered `--delete` sync must leave alone at the destination
+    const FILTERED: &[(&str, &str)] = &[
+        ("gone/.secret", "hidden"),
+        ("gone/debug.log", "excluded"),
+        (".cache/blob", "inside a hidden folder"),
+    ];
+
+    fn filtered_delete_options() -> SyncOptions {
+        SyncOptions {
+            delete: true,
+            transfer: TransferOptions {
+                include_hidden: false,
+                exclude: vec!["*.log".to_string()],
+                ..TransferOptions::default()
+            },
+            ..SyncOptions::default()
+        }
+    }
+
+    fn assert_filtered_delete(destination: &Path, report: &SyncReport) {
+        let mut deleted: Vec<&str> = report.deleted.iter().map(|p| p.to_str().unwrap()).collect();
+        deleted.sort();
+        assert_eq!(deleted, ["empty", "empty/b.txt", "empty/sub", "empty/sub/c.txt", "gone/a.txt"]);
+        for (rel, _) in FILTERED {
+            assert!(destination.join(rel).exists(), "{} was deleted", rel);
+        }
+        assert!(!destination.join("empty").exists());
+        assert!(destination.join("keep.txt").exists());
+    }
+
+    #[test]
+    fn push_delete_spares_filtered_entries_in_removed_folders() {
+        let (agent, _) = HostGuest::agent();
+        let (_dir, local, guest, guest_path) = folders();
+        write_tree(&local, &[("keep.txt", "keep")]);
+        write_tree(&guest, &[("keep.txt", "keep"), ("gone/a.txt", "a"), ("empty/b.txt", "b"), ("empty/sub/c.txt", "c")]);
+        write_tree(&guest, FILTERED);
+
+        let report = agent.sync_to_guest(&local, &guest_path, &filtered_delete_options()).unwrap();
+        assert_filtered_delete(&guest, &report);
+    }
+
+    #[test]
+    fn pull_delete_spares_filtered_entries_in_removed_folders() {
+        let (agent, _) = HostGuest::agent();
+        let (_dir, local, guest, guest_path) = folders();
+        write_tree(&guest, &[("keep.txt", "keep")]);
+        write_tree(&local, &[("keep.txt", "keep"), ("gone/a.txt", "a"), ("empty/b.txt", "b"), ("empty/sub/c.txt", "c")]);
+        write_tree(&local, FILTERED);
+
+        let report = agent.sync_from_guest(&guest_path, &local, &filtered_delete_options()).unwrap();
+        assert_filtered_delete(&local, &report);
+    }
+}
@@ -1,175 +1,599 @@
-use crate::colors::Tags;
+use crate::archive::ArchiveMode;
+use crate::checksum::ChecksumMismatch;
+use crate::chunking::{Chunker, MAX_CHUNK_SIZE};
+use crate::error::QgaError;
+use crate::events::TransferEvent;
+use crate::filter::{rel_key, PathFilter};
+use crate::listing::{local_entry_path, GuestEntry};
+use crate::metadata::{apply_local_metadata, FileMetadata, Preserve};
 use crate::qga::QemuGuestAgent;
 use anyhow::{Context, Result};
-use base64::{engine::general_purpose, Engine as _};
-use indicatif::{ProgressBar, ProgressStyle};
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
-use walkdir::WalkDir;
+use std::time::Instant;
+
+/// Outcome of a folder upload or download
+#[derive(Debug, Default)]
+pub struct FolderReport {
+    /// Files transferred
+    pub files: usize,
+    /// Files the guest refused (access denied, locked), relative to the folder, with the reason
+    pub skipped: Vec<(PathBuf, String)>,
+    /// Files transferred whose SHA-256 differs between host and guest (with `verify`)
+    pub mismatched: Vec<ChecksumMismatch>,
+}
+
+/// How a file or folder transfer behaves
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    /// Continue from the data already at the destination instead of starting over
+    pub resume: bool,
+    /// When resuming, how many bytes before the resume point must match on both
+    /// sides; a mismatch restarts the file from zero
+    pub verify_tail: u64,
+    /// Compare SHA-256 of both copies after each file
+    pub verify: bool,
+    /// Upload through a staging file and rename it into place; needs `guest-exec`
+    /// and falls back to writing the destination directly without it
+    pub atomic: bool,
+    /// Leave partial output in place when a transfer is cancelled or fails
+    /// (implied by `resume`, which needs it)
+    pub keep_partial: bool,
+    /// Files a folder transfer moves concurrently
+    pub jobs: usize,
+    /// Whether folder transfers go through a single tar archive
+    pub archive: ArchiveMode,
+    /// Update existing guest files by sending only data the guest copy lacks;
+    /// the guest rebuilds the file in the staging file, or patches it in place
+    /// without `guest-exec`
+    pub delta: bool,
+    /// Include hidden entries in folder transfers: dotfiles, and on Windows
+    /// guests anything with the hidden attribute
+    pub include_hidden: bool,
+    /// File metadata carried over to the destination
+    pub preserve: Preserve,
+    /// Glob patterns a folder transfer's files must match, if any are given
+    pub include: Vec<String>,
+    /// Glob patterns for files and folders a folder transfer leaves out
+    pub exclude: Vec<String>,
+    /// Leave out what `.gitignore` and `.ignore` files in the folder ignore
+    pub ignore_files: bool,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            resume: false,
+            verify_tail: 0,
+            verify: false,
+            atomic: true,
+            keep_partial: false,
+            jobs: 1,
+            archive: ArchiveMode::default(),
+            delta: false,
+            include_hidden: true,
+            preserve: Preserve::default(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            ignore_files: false,
+        }
+    }
+}
+
+impl TransferOptions {
+    /// Options for the single files of a folder transfer, whose metadata is
+    /// applied in a batch once the files are in place
+    pub(crate) fn per_file(&self) -> Self {
+        Self {
+            preserve: Preserve::default(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Agent commands each transfer strategy depends on
+pub(crate) const EXEC_COMMANDS: &[&str] = &["guest-exec", "guest-exec-status"];
+pub(crate) const FILE_READ_COMMANDS: &[&str] = &["guest-file-open", "guest-file-read", "guest-file-close"];
+const FILE_WRITE_COMMANDS: &[&str] = &["guest-file-open", "guest-file-write", "guest-file-close"];
 
 impl QemuGuestAgent {
     pub fn check_is_directory(&self, path: &str) -> Result<bool> {
-        let pid = if Self::is_windows_path(path) {
-            let ps_cmd = format!(
-                r#"if (Test-Path -Path "{}" -PathType Container) {{ exit 0 }} else {{ exit 1 }}"#,
-                path
-            );
-            self.guest_exec("powershell.exe", Some(vec!["-Command".to_string(), ps_cmd]), false)?
+        if !self.can_exec() {
+            return self.probe_is_directory(path);
+        }
+
+        let output = if Self::is_windows_path(path) {
+            self.powershell(format!(
+                "if (Test-Path -LiteralPath '{}' -PathType Container) {{ exit 0 }} else {{ exit 1 }}",
+                path.replace('\'', "''")
+            ))?
         } else {
-            self.guest_exec("test", Some(vec!["-d".to_string(), path.to_string()]), false)?
+            self.exec_output("test", &["-d".to_string(), path.to_string()])?
         };
 
-        for _ in 0..10 {
-            let status = self.guest_exec_status(pid)?;
-            if status.exited {
-                return Ok(status.exitcode.unwrap_or(1) == 0);
+        Ok(output.success())
+    }
+
+    /// Whether anything exists at `path`. Without `guest-exec` this opens the
+    /// path, so a guest that refuses to open it reads as missing.
+    pub fn path_exists(&self, path: &str) -> Result<bool> {
+        if !self.can_exec() {
+            return match self.open_file(&Self::guest_file_path(path), "r") {
+                Ok(file) => file.close().map(|_| true),
+                Err(err) if matches!(QgaError::find_in(&err), Some(QgaError::Guest { .. })) => Ok(false),
+                Err(err) => Err(err),
+            };
+        }
+
+        let output = if Self::is_windows_path(path) {
+            self.powershell(format!(
+                "if (Test-Path -LiteralPath '{}') {{ exit 0 }} else {{ exit 1 }}",
+                path.replace('\'', "''")
+            ))?
+        } else {
+            self.exec_output("test", &["-e".to_string(), path.to_string()])?
+        };
+        Ok(output.success())
+    }
+
+    /// Tells files from directories without `guest-exec`: a file can be opened and
+    /// read, while reading a directory handle fails. Windows refuses to open a
+    /// directory at all, which looks the same as a missing or locked file, so an
+    /// open failure is an error rather than a guess.
+    fn probe_is_directory(&self, path: &str) -> Result<bool> {
+        self.require_commands(FILE_READ_COMMANDS)?;
+
+        let normalized = Self::guest_file_path(path);
+
+        let mut file = self.open_file(&normalized, "r").with_context(|| {
+            if Self::is_windows_path(path) {
+                format!(
+                    "Cannot open {}: it is missing, locked or a folder, and telling them apart needs guest-exec",
+                    path
+                )
+            } else {
+                format!("Cannot tell whether {} is a directory: guest-exec is unavailable and opening it failed", path)
             }
-            thread::sleep(Duration::from_millis(100));
+        })?;
+        let readable = file.read(&mut [0u8; 1]).is_ok();
+        file.close()?;
+
+        Ok(!readable)
+    }
+
+    pub fn create_directory(&self, path: &str) -> Result<()> {
+        self.require_commands(EXEC_COMMANDS)
+            .with_context(|| format!("Cannot create guest directory {}", path))?;
+
+        let output = if Self::is_windows_path(path) {
+            // New-Item takes -Path literally and, with -Force, accepts a folder that exists
+            self.powershell(format!(
+                "New-Item -ItemType Directory -Force -Path '{}' | Out-Null",
+                path.replace('\'', "''")
+            ))?
+        } else {
+            self.exec_output("mkdir", &["-p".to_string(), "--".to_string(), path.to_string()])?
+        };
+
+        if !output.success() {
+            anyhow::bail!(
+                "Failed to create guest directory {}: {}",
+                path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
         }
+        Ok(())
+    }
+
+    /// Moves `from` over `to` in the guest, replacing an existing file
+    pub fn rename_path(&self, from: &str, to: &str) -> Result<()> {
+        self.require_commands(EXEC_COMMANDS)
+            .with_context(|| format!("Cannot rename guest file {}", from))?;
+
+        let output = if Self::is_windows_path(to) {
+            let ps_cmd = format!(
+                "Move-Item -LiteralPath '{}' -Destination '{}' -Force",
+                from.replace('\'', "''"),
+                to.replace('\'', "''")
+            );
+            self.powershell(ps_cmd)?
+        } else {
+            self.exec_output("mv", &["-f".to_string(), "--".to_string(), from.to_string(), to.to_string()])?
+        };
 
-        Ok(false)
+        if !output.success() {
+            anyhow::bail!(
+                "Failed to move {} to {}: {}",
+                from,
+                to,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
     }
 
-    pub fn list_directory(&self, path: &str) -> Result<Vec<String>> {
-        let pid = if Self::is_windows_path(path) {
-            self.guest_exec(
-                "cmd.exe",
-                Some(vec!["/c".to_string(), "dir".to_string(), "/b".to_string(), path.to_string()]),
-                true,
-            )?
+    /// Moves a staging file over `guest_path`. A file it replaces hands down its
+    /// owner and mode (its ACL on Windows), which the rename would otherwise
+    /// reset to those of a new file.
+    pub(crate) fn replace_with_staging(&self, staging: &str, guest_path: &str) -> Result<()> {
+        self.require_commands(EXEC_COMMANDS)
+            .with_context(|| format!("Cannot rename guest file {}", staging))?;
+
+        let output = if Self::is_windows_path(guest_path) {
+            let from = staging.replace('\'', "''");
+            let to = guest_path.replace('\'', "''");
+            self.powershell(format!(
+                "$ErrorActionPreference = 'Stop'; \
+                 if (Test-Path -LiteralPath '{to}') {{ Set-Acl -LiteralPath '{from}' -AclObject (Get-Acl -LiteralPath '{to}') }}; \
+                 Move-Item -LiteralPath '{from}' -Destination '{to}' -Force"
+            ))?
         } else {
-            self.guest_exec("ls", Some(vec!["-1".to_string(), path.to_string()]), true)?
+            // chown clears setuid bits, so it goes before chmod
+            let script = r#"if [ -e "$2" ]; then chown --reference="$2" -- "$1" && chmod --reference="$2" -- "$1" || exit; fi
+mv -f -- "$1" "$2""#;
+            let args = ["-c", script, "sh", staging, guest_path];
+            self.exec_output("sh", &args.map(String::from))?
         };
 
-        for _ in 0..20 {
-            let status = self.guest_exec_status(pid)?;
-            if status.exited {
-                if status.exitcode.unwrap_or(1) == 0 {
-                    if let Some(out_data) = status.out_data {
-                        let output = general_purpose::STANDARD
-                            .decode(out_data)
-                            .context("Failed to decode output")?;
-                        let output_str = String::from_utf8_lossy(&output);
-                        return Ok(output_str
-                            .lines()
-                            .map(|s| s.trim().to_string())
-                            .filter(|s| !s.is_empty())
-                            .collect());
-                    }
-                }
-                return Ok(vec![]);
-            }
-            thread::sleep(Duration::from_millis(100));
+        if !output.success() {
+            anyhow::bail!(
+                "Failed to move {} to {}: {}",
+                staging,
+                guest_path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
         }
+        Ok(())
+    }
+
+    pub fn remove_file(&self, path: &str) -> Result<()> {
+        self.require_commands(EXEC_COMMANDS)
+            .with_context(|| format!("Cannot remove guest file {}", path))?;
+
+        let output = if Self::is_windows_path(path) {
+            let ps_cmd = format!("Remove-Item -LiteralPath '{}' -Force", path.replace('\'', "''"));
+            self.powershell(ps_cmd)?
+        } else {
+            self.exec_output("rm", &["-f".to_string(), "--".to_string(), path.to_string()])?
+        };
 
-        Ok(vec![])
+        if !output.success() {
+            anyhow::bail!("Failed to remove {}: {}", path, String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(())
     }
 
-    pub fn create_directory(&self, path: &str) -> Result<()> {
-        let pid = if Self::is_windows_path(path) {
-            self.guest_exec(
-                "cmd.exe",
-                Some(vec!["/c".to_string(), "mkdir".to_string(), path.to_string()]),
-                false,
-            )?
+    /// Removes a guest directory only if it is empty; returns whether it was removed
+    pub fn remove_empty_directory(&self, path: &str) -> Result<bool> {
+        self.require_commands(EXEC_COMMANDS)
+            .with_context(|| format!("Cannot remove guest directory {}", path))?;
+
+        let output = if Self::is_windows_path(path) {
+            let literal = path.replace('\'', "''");
+            self.powershell(format!(
+                "$ErrorActionPreference = 'Stop'; \
+                 if (-not (Get-ChildItem -LiteralPath '{literal}' -Force | Select-Object -First 1)) {{ \
+                     Remove-Item -LiteralPath '{literal}' -Force; 'removed' \
+                 }}"
+            ))?
         } else {
-            self.guest_exec("mkdir", Some(vec!["-p".to_string(), path.to_string()]), false)?
+            let args = [path, "-maxdepth", "0", "-empty", "-delete", "-print"];
+            self.exec_output("find", &args.map(String::from))?
         };
 
-        for _ in 0..10 {
-            let status = self.guest_exec_status(pid)?;
-            if status.exited {
-                break;
-            }
-            thread::sleep(Duration::from_millis(100));
+        if !output.success() {
+            anyhow::bail!("Failed to remove {}: {}", path, String::from_utf8_lossy(&output.stderr).trim());
         }
+        Ok(!output.stdout.trim_ascii().is_empty())
+    }
 
+    /// Removes a guest file or directory with everything below it
+    pub fn remove_tree(&self, path: &str) -> Result<()> {
+        self.require_commands(EXEC_COMMANDS)
+            .with_context(|| format!("Cannot remove guest path {}", path))?;
+
+        let output = if Self::is_windows_path(path) {
+            let ps_cmd = format!("Remove-Item -LiteralPath '{}' -Recurse -Force", path.replace('\'', "''"));
+            self.powershell(ps_cmd)?
+        } else {
+            self.exec_output("rm", &["-rf".to_string(), "--".to_string(), path.to_string()])?
+        };
+
+        if !output.success() {
+            anyhow::bail!("Failed to remove {}: {}", path, String::from_utf8_lossy(&output.stderr).trim());
+        }
         Ok(())
     }
 
     pub fn upload_file(&self, local_path: &Path, guest_path: &str) -> Result<()> {
-        let guest_path_normalized = if Self::is_windows_path(guest_path) {
-            Self::normalize_windows_path(guest_path)
-        } else {
-            guest_path.to_string()
+        self.upload_file_with(local_path, guest_path, &TransferOptions::default())
+    }
+
+    /// Uploads one file. With `options.atomic` and `guest-exec` available the data
+    /// goes to a sibling staging file that is renamed over `guest_path` only once
+    /// every chunk (and the optional verification) succeeded.
+    pub fn upload_file_with(&self, local_path: &Path, guest_path: &str, options: &TransferOptions) -> Result<()> {
+        self.upload_file_data(local_path, guest_path, options)?;
+
+        if options.preserve.any() {
+            let metadata = FileMetadata::local(local_path)?;
+            self.apply_guest_metadata(&[(guest_path.to_string(), metadata)], &options.preserve)?;
+        }
+        Ok(())
+    }
+
+    fn upload_file_data(&self, local_path: &Path, guest_path: &str, options: &TransferOptions) -> Result<()> {
+        self.require_commands(FILE_WRITE_COMMANDS)?;
+
+        // Send only what the existing guest file lacks; a missing one gets a full upload
+        if options.delta && !options.resume && self.path_exists(guest_path)? {
+            return self.upload_file_delta(local_path, guest_path);
+        }
+
+        let keep_partial = options.keep_partial || options.resume;
+        let can_exec = self.can_exec();
+        let staging = (options.atomic && can_exec).then(|| Self::staging_path(guest_path));
+        let Some(staging) = staging else {
+            let result = self.upload_into(local_path, guest_path, options);
+            // Without a staging file the destination itself is the partial output
+            if result.is_err() && self.cancel_token().is_cancelled() && !keep_partial && can_exec {
+                let _ = self.remove_file(guest_path);
+            }
+            return result;
         };
 
-        let handle = self.guest_file_open(&guest_path_normalized, "w+")?;
+        // A finished upload renamed its staging file away, so with none to
+        // continue a destination that is already whole is left alone
+        if options.resume
+            && self.open_file(&Self::guest_file_path(&staging), "r").is_err()
+            && self.upload_is_complete(local_path, guest_path, options.verify_tail)?
+        {
+            return self.upload_into(local_path, guest_path, options);
+        }
+
+        if let Err(err) = self.upload_into(local_path, &staging, options) {
+            // A resumed upload picks the staging file up again next time
+            if !keep_partial {
+                let _ = self.remove_file(&staging);
+            }
+            return Err(err);
+        }
+
+        self.replace_with_staging(&staging, guest_path).inspect_err(|err| {
+            self.emit(TransferEvent::Error {
+                path: local_path.display().to_string(),
+                message: format!("{:#}", err),
+            });
+        })
+    }
+
+    /// Hidden sibling of `guest_path` that atomic uploads write to first. The name
+    /// is fixed so `--resume` finds it again.
+    pub(crate) fn staging_path(guest_path: &str) -> String {
+        match guest_path.rfind(['/', '\\']) {
+            Some(pos) => format!("{}.{}.qga-part", &guest_path[..=pos], &guest_path[pos + 1..]),
+            None => format!(".{}.qga-part", guest_path),
+        }
+    }
+
+    fn upload_into(&self, local_path: &Path, guest_path: &str, options: &TransferOptions) -> Result<()> {
+        let guest_path_normalized = Self::guest_file_path(guest_path);
+
+        let event_path = local_path.display().to_string();
+        let size = fs::metadata(local_path).ok().map(|m| m.len());
+        self.emit(TransferEvent::FileStarted { path: event_path.clone(), size });
 
         let result = (|| -> Result<()> {
             let mut file = fs::File::open(local_path)?;
-            let mut buffer = vec![0u8; 4096];
+            let offset = if options.resume {
+                let local_len = file.metadata()?.len();
+                self.upload_resume_offset(&guest_path_normalized, &mut file, local_len, options.verify_tail)?
+            } else {
+                0
+            };
+
+            // Comparing tails moved the local position, also when resuming was refused
+            file.seek(SeekFrom::Start(offset))?;
+            let mut guest_file = if offset > 0 {
+                let mut guest_file = self.open_file(&guest_path_normalized, "r+")?;
+                guest_file.seek(SeekFrom::Start(offset))?;
+                self.emit(TransferEvent::BytesTransferred { path: event_path.clone(), bytes: offset });
+                guest_file
+            } else {
+                self.open_file(&guest_path_normalized, "w+")?
+            };
+
+            let mut chunker = Chunker::new(self.chunk_size, self.write_chunk_limit());
+            let mut buffer = Vec::new();
 
             loop {
+                self.check_cancelled()?;
+                buffer.resize(chunker.size(), 0);
                 let bytes_read = file.read(&mut buffer)?;
                 if bytes_read == 0 {
                     break;
                 }
-                self.guest_file_write(handle, &buffer[..bytes_read])?;
+                let started = Instant::now();
+                guest_file.write_all(&buffer[..bytes_read])?;
+                chunker.record(started.elapsed());
+                self.emit(TransferEvent::BytesTransferred { path: event_path.clone(), bytes: bytes_read as u64 });
             }
 
-            Ok(())
+            guest_file.close()
         })();
 
-        self.guest_file_close(handle)?;
-        result
+        self.emit_outcome(event_path.clone(), &result);
+        result?;
+
+        if options.verify {
+            self.verify_transfer(event_path, local_path, guest_path)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `guest_path` already holds all of a non-empty `local_path`, as
+    /// far as its size and `verify_tail` bytes before its end tell
+    fn upload_is_complete(&self, local_path: &Path, guest_path: &str, verify_tail: u64) -> Result<bool> {
+        let mut file = fs::File::open(local_path)?;
+        let local_len = file.metadata()?.len();
+        if local_len == 0 {
+            return Ok(false);
+        }
+        let offset = self.upload_resume_offset(&Self::guest_file_path(guest_path), &mut file, local_len, verify_tail)?;
+        Ok(offset == local_len)
+    }
+
+    /// Where an upload can pick up: the size of the existing guest file, unless it
+    /// is longer than the local file or its tail does not match
+    fn upload_resume_offset(&self, guest_path: &str, local: &mut fs::File, local_len: u64, verify_tail: u64) -> Result<u64> {
+        let Ok(mut guest_file) = self.open_file(guest_path, "r") else {
+            return Ok(0);
+        };
+
+        let guest_len = guest_file.seek(SeekFrom::End(0))?;
+        if guest_len > local_len || !tails_match(&mut guest_file, local, guest_len, verify_tail)? {
+            return Ok(0);
+        }
+
+        guest_file.close()?;
+        Ok(guest_len)
     }
 
     pub fn download_file(&self, guest_path: &str, local_path: &Path) -> Result<()> {
+        self.download_file_with(guest_path, local_path, &TransferOptions::default())
+    }
+
+    pub fn download_file_with(&self, guest_path: &str, local_path: &Path, options: &TransferOptions) -> Result<()> {
+        self.download_file_data(guest_path, local_path, options)?;
+
+        if options.preserve.any() {
+            let entry = self.stat_guest(guest_path)?;
+            apply_local_metadata(local_path, &FileMetadata::from(&entry), &options.preserve)?;
+        }
+        Ok(())
+    }
+
+    fn download_file_data(&self, guest_path: &str, local_path: &Path, options: &TransferOptions) -> Result<()> {
+        self.require_commands(FILE_READ_COMMANDS)?;
+
         if let Some(parent) = local_path.parent() {
             fs::create_dir_all(parent)?;
         }
 
-        let guest_path_normalized = if Self::is_windows_path(guest_path) {
-            Self::normalize_windows_path(guest_path)
-        } else {
-            guest_path.to_string()
-        };
+        let guest_path_normalized = Self::guest_file_path(guest_path);
 
-        let handle = self.guest_file_open(&guest_path_normalized, "r")?;
+        let event_path = guest_path.to_string();
+        self.emit(TransferEvent::FileStarted { path: event_path.clone(), size: None });
 
         let result = (|| -> Result<()> {
-            let mut file = fs::File::create(local_path)?;
+            let mut guest_file = self.open_file(&guest_path_normalized, "r")?;
 
-            loop {
-                let (data, eof) = self.guest_file_read(handle, 4096)?;
-                if !data.is_empty() {
-                    file.write_all(&data)?;
+            let existing = if options.resume {
+                fs::OpenOptions::new().read(true).write(true).open(local_path).ok()
+            } else {
+                None
+            };
+
+            let mut file = match existing {
+                Some(mut file) => {
+                    let local_len = file.metadata()?.len();
+                    let guest_len = guest_file.seek(SeekFrom::End(0))?;
+                    let offset = if local_len <= guest_len
+                        && tails_match(&mut guest_file, &mut file, local_len, options.verify_tail)?
+                    {
+                        local_len
+                    } else {
+                        0
+                    };
+
+                    file.set_len(offset)?;
+                    file.seek(SeekFrom::Start(offset))?;
+                    guest_file.seek(SeekFrom::Start(offset))?;
+                    if offset > 0 {
+                        self.emit(TransferEvent::BytesTransferred { path: event_path.clone(), bytes: offset });
+                    }
+                    file
                 }
-                if eof {
+                None => fs::File::create(local_path)?,
+            };
+
+            let mut chunker = Chunker::new(self.chunk_size, MAX_CHUNK_SIZE);
+            let mut buffer = Vec::new();
+
+            loop {
+                self.check_cancelled()?;
+                buffer.resize(chunker.size(), 0);
+                let started = Instant::now();
+                let bytes_read = guest_file.read(&mut buffer)?;
+                chunker.record(started.elapsed());
+                if bytes_read == 0 {
                     break;
                 }
+                file.write_all(&buffer[..bytes_read])?;
+                self.emit(TransferEvent::BytesTransferred { path: event_path.clone(), bytes: bytes_read as u64 });
             }
 
-            Ok(())
+            guest_file.close()
         })();
 
-        self.guest_file_close(handle)?;
-        result
+        if result.is_err() && self.cancel_token().is_cancelled() && !(options.keep_partial || options.resume) {
+            let _ = fs::remove_file(local_path);
+        }
+
+        self.emit_outcome(event_path.clone(), &result);
+        result?;
+
+        if options.verify {
+            self.verify_transfer(event_path, local_path, guest_path)?;
+        }
+        Ok(())
     }
 
-    pub fn upload_folder(&self, local_folder: &Path, guest_folder: &str) -> Result<()> {
-        println!("{} Uploading folder: {} -> {}", Tags::upload(), local_folder.display(), guest_folder);
+    pub(crate) fn emit_outcome(&self, path: String, result: &Result<()>) {
+        match result {
+            Ok(()) => self.emit(TransferEvent::FileFinished { path }),
+            Err(err) => self.emit(TransferEvent::Error { path, message: format!("{:#}", err) }),
+        }
+    }
 
-        self.create_directory(guest_folder)?;
+    pub(crate) fn verify_transfer(&self, event_path: String, local_path: &Path, guest_path: &str) -> Result<()> {
+        let result = self.verify_file(local_path, guest_path);
+        if let Some(mismatch) = result.as_ref().err().and_then(|e| e.downcast_ref::<ChecksumMismatch>()) {
+            self.emit(TransferEvent::Mismatch {
+                path: event_path,
+                local: mismatch.local.clone(),
+                guest: mismatch.guest.clone(),
+            });
+        }
+        result
+    }
 
-        let entries: Vec<_> = WalkDir::new(local_folder)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .collect();
+    pub fn upload_folder(&self, local_folder: &Path, guest_folder: &str) -> Result<FolderReport> {
+        self.upload_folder_with(local_folder, guest_folder, &TransferOptions::default())
+    }
 
-        let pb = ProgressBar::new(entries.len() as u64);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}")
-                .unwrap()
-                .progress_chars("=>-"),
-        );
+    pub fn upload_folder_with(
+        &self,
+        local_folder: &Path,
+        guest_folder: &str,
+        options: &TransferOptions,
+    ) -> Result<FolderReport> {
+        // Folder uploads need guest-exec for mkdir; fail before any file is sent
+        self.require_commands(FILE_WRITE_COMMANDS)?;
+        self.require_commands(EXEC_COMMANDS)
+            .context("Folder uploads create directories through guest-exec")?;
 
-        for entry in entries {
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        let mut bytes = 0;
+
+        let mut filter = PathFilter::new(options)?;
+        for entry in filter.walk_local(local_folder)?.into_iter().filter_map(|e| e.ok()) {
+            self.check_cancelled()?;
             let path = entry.path();
             let rel_path = path.strip_prefix(local_folder)?;
 
@@ -177,51 +601,649 @@ impl QemuGuestAgent {
                 continue;
             }
 
-            let guest_path = if Self::is_windows_path(guest_folder) {
-                format!("{}\\{}", guest_folder, rel_path.to_string_lossy().replace('/', "\\"))
-            } else {
-                format!("{}/{}", guest_folder, rel_path.to_string_lossy())
-            };
+            let guest_path = Self::join_guest_path(guest_folder, &rel_key(rel_path));
 
-            if path.is_dir() {
-                self.create_directory(&guest_path)?;
-            } else {
-                pb.set_message(format!("Uploading {}", rel_path.display()));
-                self.upload_file(path, &guest_path)?;
+            if entry.file_type().is_dir() {
+                dirs.push(FileJob {
+                    local: path.to_path_buf(),
+                    guest: guest_path,
+                    rel: rel_path.to_path_buf(),
+                });
+            } else if entry.file_type().is_file() {
+                bytes += entry.metadata().map(|m| m.len()).unwrap_or(0);
+                files.push(FileJob {
+                    local: path.to_path_buf(),
+                    guest: guest_path,
+                    rel: rel_path.to_path_buf(),
+                });
             }
+        }
 
-            pb.inc(1);
+        if let Some(needed) = filter.needed_dirs(files.iter().map(|f| rel_key(&f.rel))) {
+            dirs.retain(|d| needed.contains(&rel_key(&d.rel)));
         }
 
-        pb.finish_with_message(format!("{} Upload complete", Tags::ok()));
-        Ok(())
+        let per_file = options.per_file();
+        if options.archive.wants(files.len()) {
+            match self.upload_folder_archive(&dirs, &files, guest_folder, &per_file) {
+                Ok(report) => {
+                    let all: Vec<&FileJob> = files.iter().collect();
+                    self.preserve_uploaded_folder(local_folder, guest_folder, &all, &dirs, &options.preserve)?;
+                    self.emit(TransferEvent::FolderFinished { files: report.files });
+                    return Ok(report);
+                }
+                Err(err) if !self.archive_falls_back(options.archive, &err) => return Err(err),
+                // The guest has no usable tar; send the files one by one instead
+                Err(_) => {}
+            }
+        }
+
+        // Directories are created up front, in walk order, so parallel file uploads find their parents
+        self.create_directory(guest_folder)?;
+        for dir in &dirs {
+            self.check_cancelled()?;
+            self.create_directory(&dir.guest)?;
+        }
+
+        self.emit(TransferEvent::FolderScanned { files: files.len(), bytes: Some(bytes) });
+
+        let outcomes = self.run_jobs(&files, options.jobs, |job| {
+            let outcome = FileOutcome::from(self.upload_file_with(&job.local, &job.guest, &per_file));
+            if let FileOutcome::Skipped(reason) = &outcome {
+                self.emit(TransferEvent::Skipped { path: job.local.display().to_string(), reason: reason.clone() });
+            }
+            outcome
+        });
+
+        let done = done_jobs(&files, &outcomes);
+        let report = self.collect_outcomes(&files, outcomes)?;
+        self.preserve_uploaded_folder(local_folder, guest_folder, &done, &dirs, &options.preserve)?;
+        self.emit(TransferEvent::FolderFinished { files: report.files });
+        Ok(report)
     }
 
-    pub fn download_folder(&self, guest_folder: &str, local_folder: &Path) -> Result<()> {
-        println!("{} Downloading folder: {} -> {}", Tags::download(), guest_folder, local_folder.display());
+    pub fn download_folder(&self, guest_folder: &str, local_folder: &Path) -> Result<FolderReport> {
+        self.download_folder_with(guest_folder, local_folder, &TransferOptions::default())
+    }
 
-        fs::create_dir_all(local_folder)?;
+    pub fn download_folder_with(
+        &self,
+        guest_folder: &str,
+        local_folder: &Path,
+        options: &TransferOptions,
+    ) -> Result<FolderReport> {
+        self.require_commands(FILE_READ_COMMANDS)?;
+        self.require_commands(EXEC_COMMANDS)
+            .context("Folder downloads list directories through guest-exec")?;
 
-        let items = self.list_directory(guest_folder)?;
+        // One recursive listing plans the whole download
+        let mut filter = PathFilter::new(options)?;
+        let tree = self.filter_listing(guest_folder, self.list_tree(guest_folder)?, &mut filter)?;
+        let entries = tree.entries;
+        let file_count = entries.iter().filter(|e| !e.is_dir).count();
 
-        for item in items {
-            let guest_item_path = if Self::is_windows_path(guest_folder) {
-                format!("{}\\{}", guest_folder, item)
+        // The guest packs the whole folder, so an archive cannot leave entries out
+        let per_file = options.per_file();
+        let filtered = !options.include_hidden || filter.filters_paths();
+        if !filtered && options.archive.wants(file_count) {
+            match self.download_folder_archive(guest_folder, local_folder, &per_file) {
+                Ok(report) => {
+                    let (files, dirs): (Vec<&GuestEntry>, Vec<&GuestEntry>) = entries.iter().partition(|e| !e.is_dir);
+                    self.preserve_downloaded_folder(guest_folder, local_folder, &files, &dirs, &options.preserve)?;
+                    self.emit(TransferEvent::FolderFinished { files: report.files });
+                    return Ok(report);
+                }
+                Err(err) if !self.archive_falls_back(options.archive, &err) => return Err(err),
+                Err(_) => {}
+            }
+        }
+
+        fs::create_dir_all(local_folder)?;
+        let mut files = Vec::new();
+        let mut file_entries = Vec::new();
+        let mut dir_entries = Vec::new();
+        let mut bytes = 0;
+        for entry in &entries {
+            let local_path = local_entry_path(local_folder, &entry.path)?;
+            if entry.is_dir {
+                fs::create_dir_all(&local_path)?;
+                dir_entries.push(entry);
             } else {
-                format!("{}/{}", guest_folder, item)
+                bytes += entry.size;
+                file_entries.push(entry);
+                files.push(FileJob {
+                    local: local_path,
+                    guest: Self::join_guest_path(guest_folder, &entry.path),
+                    rel: PathBuf::from(&entry.path),
+                });
+            }
+        }
+        self.emit(TransferEvent::FolderScanned { files: files.len(), bytes: Some(bytes) });
+
+        let outcomes = self.run_jobs(&files, options.jobs, |job| {
+            let outcome = FileOutcome::from(self.download_file_with(&job.guest, &job.local, &per_file));
+            if let FileOutcome::Skipped(reason) = &outcome {
+                self.emit(TransferEvent::Skipped { path: job.guest.clone(), reason: reason.clone() });
+            }
+            outcome
+        });
+
+        let done: Vec<&GuestEntry> = file_entries
+            .into_iter()
+            .zip(&outcomes)
+            .filter(|(_, outcome)| matches!(outcome, Some(FileOutcome::Done)))
+            .map(|(entry, _)| entry)
+            .collect();
+        let mut report = self.collect_outcomes(&files, outcomes)?;
+        report.skipped.extend(tree.skipped.into_iter().map(|(rel, reason)| (PathBuf::from(rel), reason)));
+        self.preserve_downloaded_folder(guest_folder, local_folder, &done, &dir_entries, &options.preserve)?;
+        self.emit(TransferEvent::FolderFinished { files: report.files });
+        Ok(report)
+    }
+
+    /// Runs `transfer` over `jobs` on up to `workers` threads, each with its own guest
+    /// file handles. Outcomes keep the order of `jobs`; after a failure or cancel no
+    /// new file is started, leaving `None` for the ones never attempted.
+    pub(crate) fn run_jobs(
+        &self,
+        jobs: &[FileJob],
+        workers: usize,
+        transfer: impl Fn(&FileJob) -> FileOutcome + Sync,
+    ) -> Vec<Option<FileOutcome>> {
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let outcomes: Vec<Mutex<Option<FileOutcome>>> = jobs.iter().map(|_| Mutex::new(None)).collect();
+
+        let worker = || loop {
+            if failed.load(Ordering::SeqCst) || self.cancel_token().is_cancelled() {
+                break;
+            }
+            let index = next.fetch_add(1, Ordering::SeqCst);
+            let Some(job) = jobs.get(index) else {
+                break;
             };
 
-            let local_item_path = local_folder.join(&item);
+            let outcome = transfer(job);
+            if matches!(outcome, FileOutcome::Failed(_)) {
+                failed.store(true, Ordering::SeqCst);
+            }
+            *outcomes[index].lock().unwrap() = Some(outcome);
+        };
 
-            if self.check_is_directory(&guest_item_path)? {
-                self.download_folder(&guest_item_path, &local_item_path)?;
-            } else {
-                println!("{} Downloading {}", Tags::file(), item);
-                self.download_file(&guest_item_path, &local_item_path)?;
+        thread::scope(|scope| {
+            for _ in 1..workers.clamp(1, jobs.len().max(1)) {
+                scope.spawn(worker);
+            }
+            worker();
+        });
+
+        outcomes.into_iter().map(|o| o.into_inner().unwrap()).collect()
+    }
+
+    /// Folds per-file outcomes into a report, failing with every error that aborted the folder
+    pub(crate) fn collect_outcomes(&self, jobs: &[FileJob], outcomes: Vec<Option<FileOutcome>>) -> Result<FolderReport> {
+        let mut report = FolderReport::default();
+        let mut errors = Vec::new();
+
+        for (job, outcome) in jobs.iter().zip(outcomes) {
+            match outcome {
+                Some(FileOutcome::Done) => report.files += 1,
+                Some(FileOutcome::Skipped(reason)) => report.skipped.push((job.rel.clone(), reason)),
+                Some(FileOutcome::Mismatch(mismatch)) => report.mismatched.push(mismatch),
+                Some(FileOutcome::Failed(err)) => errors.push((&job.rel, err)),
+                None => {}
             }
         }
 
-        println!("{} Folder downloaded successfully", Tags::ok());
-        Ok(())
+        self.check_cancelled()?;
+        if errors.len() > 1 {
+            let details: Vec<String> = errors
+                .iter()
+                .map(|(rel, err)| format!("  {}: {:#}", rel.display(), err))
+                .collect();
+            anyhow::bail!("{} files failed:\n{}", errors.len(), details.join("\n"));
+        }
+        if let Some((_, err)) = errors.pop() {
+            return Err(err);
+        }
+
+        Ok(report)
+    }
+}
+
+/// One file of a folder transfer
+pub(crate) struct FileJob {
+    pub(crate) local: PathBuf,
+    pub(crate) guest: String,
+    /// Path relative to the folder, as recorded in the report
+    pub(crate) rel: PathBuf,
+}
+
+/// How one file of a folder transfer ended
+pub(crate) enum FileOutcome {
+    Done,
+    /// The guest refused the file (access denied, locked); the folder carries on
+    Skipped(String),
+    Mismatch(ChecksumMismatch),
+    /// Anything else, including a full disk or an I/O error, aborts the folder
+    Failed(anyhow::Error),
+}
+
+impl From<Result<()>> for FileOutcome {
+    fn from(result: Result<()>) -> Self {
+        let Err(err) = result else {
+            return Self::Done;
+        };
+
+        match QgaError::find_in(&err) {
+            Some(guest_err) if guest_err.is_refusal() => Self::Skipped(guest_err.to_string()),
+            _ => match err.downcast::<ChecksumMismatch>() {
+                Ok(mismatch) => Self::Mismatch(mismatch),
+                Err(err) => Self::Failed(err),
+            },
+        }
+    }
+}
+
+/// The jobs whose file arrived intact
+pub(crate) fn done_jobs<'a>(jobs: &'a [FileJob], outcomes: &[Option<FileOutcome>]) -> Vec<&'a FileJob> {
+    jobs.iter()
+        .zip(outcomes)
+        .filter(|(_, outcome)| matches!(outcome, Some(FileOutcome::Done)))
+        .map(|(job, _)| job)
+        .collect()
+}
+
+/// Compares the `len` bytes ending at `end` in both streams
+fn tails_match(a: &mut (impl Read + Seek), b: &mut (impl Read + Seek), end: u64, len: u64) -> Result<bool> {
+    let len = len.min(end);
+    if len == 0 {
+        return Ok(true);
+    }
+
+    let mut tail_a = vec![0u8; len as usize];
+    let mut tail_b = vec![0u8; len as usize];
+    a.seek(SeekFrom::Start(end - len))?;
+    a.read_exact(&mut tail_a)?;
+    b.seek(SeekFrom::Start(end - len))?;
+    b.read_exact(&mut tail_b)?;
+
+    Ok(tail_a == tail_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::QgaResult;
+    use crate::transport::MockTransport;
+    use base64::{engine::general_purpose, Engine as _};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// Guest files behind a `MockTransport` that offers only the file commands
+    #[derive(Default)]
+    struct FakeGuest {
+        files: HashMap<String, Vec<u8>>,
+        /// Open handles with their file and position
+        handles: HashMap<i64, (String, usize)>,
+        next_handle: i64,
+        bytes_written: usize,
+        bytes_read: usize,
+        /// Flip the first byte of every write, to fail verification
+        corrupt_writes: bool,
+        /// Refuse writes, as a full guest disk would
+        refuse_writes: bool,
+        /// Paths the guest may not open
+        denied: Vec<String>,
+    }
+
+    impl FakeGuest {
+        fn handle(&mut self, command: &str, args: &Value) -> QgaResult<Value> {
+            let refuse = |desc: &str| Err(QgaError::from_guest(command, "GenericError", desc));
+            let handle = args["handle"].as_i64().unwrap_or_default();
+
+            match command {
+                "guest-info" => {
+                    let commands: Vec<Value> = ["guest-file-open", "guest-file-read", "guest-file-write"]
+                        .iter()
+                        .chain(&["guest-file-seek", "guest-file-close", "guest-file-flush"])
+                        .map(|name| json!({ "name": name, "enabled": true }))
+                        .collect();
+                    Ok(json!({ "version": "test", "supported_commands": commands }))
+                }
+                "guest-file-open" => {
+                    let path = args["path"].as_str().unwrap().to_string();
+                    if self.denied.contains(&path) {
+                        return refuse(&format!("failed to open file '{}': Permission denied", path));
+                    }
+                    match args["mode"].as_str().unwrap() {
+                        "r" | "r+" if !self.files.contains_key(&path) => return refuse("No such file or directory"),
+                        "w" | "w+" => {
+                            self.files.insert(path.clone(), Vec::new());
+                        }
+                        _ => {}
+                    }
+                    self.next_handle += 1;
+                    self.handles.insert(self.next_handle, (path, 0));
+                    Ok(json!(self.next_handle))
+                }
+                "guest-file-read" => {
+                    let (path, pos) = self.handles.get_mut(&handle).unwrap();
+                    let data = &self.files[path.as_str()];
+                    let end = (*pos + args["count"].as_u64().unwrap() as usize).min(data.len());
+                    let chunk = data[(*pos).min(end)..end].to_vec();
+                    *pos = end;
+                    self.bytes_read += chunk.len();
+                    Ok(json!({
+                        "count": chunk.len(),
+                        "buf-b64": general_purpose::STANDARD.encode(&chunk),
+                        "eof": end == data.len(),
+                    }))
+                }
+                "guest-file-write" => {
+                    if self.refuse_writes {
+                        return refuse("No space left on device");
+                    }
+                    let mut chunk = general_purpose::STANDARD.decode(args["buf-b64"].as_str().unwrap()).unwrap();
+                    if self.corrupt_writes {
+                        chunk[0] ^= 0xff;
+                    }
+                    let (path, pos) = self.handles.get_mut(&handle).unwrap();
+                    let data = self.files.get_mut(path.as_str()).unwrap();
+                    if data.len() < *pos + chunk.len() {
+                        data.resize(*pos + chunk.len(), 0);
+                    }
+                    data[*pos..*pos + chunk.len()].copy_from_slice(&chunk);
+                    *pos += chunk.len();
+                    self.bytes_written += chunk.len();
+                    Ok(json!({ "count": chunk.len(), "eof": false }))
+                }
+                "guest-file-seek" => {
+                    let (path, pos) = self.handles.get_mut(&handle).unwrap();
+                    let offset = args["offset"].as_i64().unwrap();
+                    let base = match args["whence"].as_str().unwrap() {
+                        "set" => 0,
+                        "cur" => *pos as i64,
+                        _ => self.files[path.as_str()].len() as i64,
+                    };
+                    *pos = (base + offset) as usize;
+                    Ok(json!({ "position": *pos, "eof": false }))
+                }
+                "guest-file-close" => {
+                    self.handles.remove(&handle);
+                    Ok(json!({}))
+                }
+                "guest-file-flush" => Ok(json!({})),
+                other => Err(QgaError::CommandUnsupported { command: other.to_string() }),
+            }
+        }
+    }
+
+    fn agent(guest: &Arc<Mutex<FakeGuest>>) -> QemuGuestAgent {
+        let guest = Arc::clone(guest);
+        let transport = MockTransport::new(move |command, args| guest.lock().unwrap().handle(command, args));
+        QemuGuestAgent::with_transport("test".to_string(), Box::new(transport))
+    }
+
+    /// Bytes that differ from one position to the next
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn local_file(dir: &tempfile::TempDir, contents: &[u8]) -> PathBuf {
+        let path = dir.path().join("local.bin");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn upload_and_download_round_trip() {
+        let guest = Arc::new(Mutex::new(FakeGuest::default()));
+        let agent = agent(&guest);
+        let dir = tempfile::tempdir().unwrap();
+        let contents = sample(10_000);
+        let local = local_file(&dir, &contents);
+        let options = TransferOptions { verify: true, ..TransferOptions::default() };
+
+        agent.upload_file_with(&local, "/tmp/file.bin", &options).unwrap();
+        assert_eq!(guest.lock().unwrap().files["/tmp/file.bin"], contents);
+
+        let downloaded = dir.path().join("nested").join("downloaded.bin");
+        agent.download_file_with("/tmp/file.bin", &downloaded, &options).unwrap();
+        assert_eq!(fs::read(&downloaded).unwrap(), contents);
+        assert!(guest.lock().unwrap().handles.is_empty(), "every guest handle is closed");
+    }
+
+    #[test]
+    fn resumed_upload_sends_only_the_rest() {
+        let guest = Arc::new(Mutex::new(FakeGuest::default()));
+        let contents = sample(10_000);
+        guest.lock().unwrap().files.insert("/tmp/file.bin".to_string(), contents[..3_000].to_vec());
+        let agent = agent(&guest);
+        let dir = tempfile::tempdir().unwrap();
+        let local = local_file(&dir, &contents);
+        let options = TransferOptions { resume: true, verify_tail: 64, ..TransferOptions::default() };
+
+        agent.upload_file_with(&local, "/tmp/file.bin", &options).unwrap();
+        let guest = guest.lock().unwrap();
+        assert_eq!(guest.files["/tmp/file.bin"], contents);
+        assert_eq!(guest.bytes_written, 7_000);
+    }
+
+    #[test]
+    fn resumed_upload_starts_over_when_the_tail_differs() {
+        let guest = Arc::new(Mutex::new(FakeGuest::default()));
+        let contents = sample(10_000);
+        let mut partial = contents[..3_000].to_vec();
+        partial[2_990] ^= 0xff;
+        guest.lock().unwrap().files.insert("/tmp/file.bin".to_string(), partial);
+        let agent = agent(&guest);
+        let dir = tempfile::tempdir().unwrap();
+        let local = local_file(&dir, &contents);
+        let options = TransferOptions { resume: true, verify_tail: 64, ..TransferOptions::default() };
+
+        agent.upload_file_with(&local, "/tmp/file.bin", &options).unwrap();
+        let guest = guest.lock().unwrap();
+        assert_eq!(guest.files["/tmp/file.bin"], contents);
+        assert_eq!(guest.bytes_written, 10_000);
+    }
+
+    #[test]
+    fn resumed_upload_leaves_a_complete_file_alone() {
+        let guest = Arc::new(Mutex::new(FakeGuest::default()));
+        let contents = sample(10_000);
+        guest.lock().unwrap().files.insert("/tmp/file.bin".to_string(), contents.clone());
+        let agent = agent(&guest);
+        let dir = tempfile::tempdir().unwrap();
+        let local = local_file(&dir, &contents);
+        let options = TransferOptions { resume: true, ..TransferOptions::default() };
+
+        agent.upload_file_with(&local, "/tmp/file.bin", &options).unwrap();
+        assert_eq!(guest.lock().unwrap().bytes_written, 0);
+    }
+
+    #[test]
+    fn resumed_download_fetches_only_the_rest() {
+        let guest = Arc::new(Mutex::new(FakeGuest::default()));
+        let contents = sample(10_000);
+        guest.lock().unwrap().files.insert("/tmp/file.bin".to_string(), contents.clone());
+        let agent = agent(&guest);
+        let dir = tempfile::tempdir().unwrap();
+        let local = local_file(&dir, &contents[..4_000]);
+        let options = TransferOptions { resume: true, verify_tail: 64, ..TransferOptions::default() };
+
+        agent.download_file_with("/tmp/file.bin", &local, &options).unwrap();
+        assert_eq!(fs::read(&local).unwrap(), contents);
+        // The compared tail plus the missing part
+        assert_eq!(guest.lock().unwrap().bytes_read, 64 + 6_000);
+    }
+
+    #[test]
+    fn verify_reports_a_corrupted_upload() {
+        let guest = Arc::new(Mutex::new(FakeGuest { corrupt_writes: true, ..FakeGuest::default() }));
+        let agent = agent(&guest);
+        let dir = tempfile::tempdir().unwrap();
+        let local = local_file(&dir, &sample(5_000));
+        let options = TransferOptions { verify: true, ..TransferOptions::default() };
+
+        let err = agent.upload_file_with(&local, "/tmp/file.bin", &options).unwrap_err();
+        let mismatch = err.downcast_ref::<ChecksumMismatch>().expect("a checksum mismatch");
+        assert_eq!(mismatch.guest_path, "/tmp/file.bin");
+        assert_ne!(mismatch.local, mismatch.guest);
+    }
+
+    #[test]
+    fn staging_file_sits_beside_the_destination() {
+        assert_eq!(QemuGuestAgent::staging_path("/tmp/file.bin"), "/tmp/.file.bin.qga-part");
+        assert_eq!(QemuGuestAgent::staging_path("C:\\Temp\\file.bin"), "C:\\Temp\\.file.bin.qga-part");
+        assert_eq!(QemuGuestAgent::staging_path("file.bin"), ".file.bin.qga-part");
+    }
+
+    #[test]
+    fn windows_directory_check_takes_the_path_literally() {
+        let scripts = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&scripts);
+        let transport = MockTransport::new(move |command, args| match command {
+            "guest-exec" => {
+                recorded.lock().unwrap().push(args["arg"][2].as_str().unwrap().to_string());
+                Ok(json!({ "pid": 1 }))
+            }
+            "guest-exec-status" => Ok(json!({ "exited": true, "exitcode": 0 })),
+            // Without guest-info every command is assumed available
+            other => Err(QgaError::CommandUnsupported { command: other.to_string() }),
+        });
+        let agent = QemuGuestAgent::with_transport("test".to_string(), Box::new(transport));
+
+        assert!(agent.check_is_directory("C:\\Bob's \"$HOME\" `n [1]").unwrap());
+        assert_eq!(
+            scripts.lock().unwrap()[0],
+            "if (Test-Path -LiteralPath 'C:\\Bob''s \"$HOME\" `n [1]' -PathType Container) { exit 0 } else { exit 1 }"
+        );
+    }
+
+    #[test]
+    fn failed_mkdir_stops_a_folder_upload() {
+        let (agent, _) = crate::host_guest::HostGuest::agent();
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("local");
+        fs::create_dir_all(local.join("sub")).unwrap();
+        fs::write(local.join("sub/a.txt"), "a").unwrap();
+        // A file where the guest folder should go
+        let guest = dir.path().join("guest");
+        fs::write(&guest, "in the way").unwrap();
+        let options = TransferOptions { archive: ArchiveMode::Never, ..TransferOptions::default() };
+
+        let err = agent.upload_folder_with(&local, guest.to_str().unwrap(), &options).unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to create guest directory"), "{:#}", err);
+    }
+
+    #[test]
+    fn atomic_upload_keeps_the_mode_and_owner_of_the_file_it_replaces() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let (agent, _) = crate::host_guest::HostGuest::agent();
+        let dir = tempfile::tempdir().unwrap();
+        let local = local_file(&dir, b"#!/bin/sh\necho new\n");
+        let guest = dir.path().join("tool.sh");
+        fs::write(&guest, "#!/bin/sh\necho old\n").unwrap();
+        fs::set_permissions(&guest, fs::Permissions::from_mode(0o751)).unwrap();
+        // Only root can hand the file to someone else
+        let owner = std::os::unix::fs::chown(&guest, Some(4321), Some(4321)).map(|_| 4321).ok();
+
+        agent.upload_file(&local, guest.to_str().unwrap()).unwrap();
+        let metadata = fs::metadata(&guest).unwrap();
+        assert_eq!(fs::read(&guest).unwrap(), b"#!/bin/sh\necho new\n");
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o751);
+        if let Some(owner) = owner {
+            assert_eq!((metadata.uid(), metadata.gid()), (owner, owner));
+        }
+        assert!(!Path::new(&QemuGuestAgent::staging_path(guest.to_str().unwrap())).exists());
+    }
+
+    #[test]
+    fn folder_download_keeps_odd_names_and_reports_unlisted_ones() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let (agent, _) = crate::host_guest::HostGuest::agent();
+        let dir = tempfile::tempdir().unwrap();
+        let guest = dir.path().join("guest");
+        fs::create_dir_all(guest.join(" spaced")).unwrap();
+        fs::write(guest.join(" spaced/line\nbreak"), "a").unwrap();
+        fs::write(guest.join(".hidden"), "b").unwrap();
+        fs::write(guest.join(OsStr::from_bytes(b"caf\xe9")), "c").unwrap();
+        let local = dir.path().join("local");
+        let options = TransferOptions { archive: ArchiveMode::Never, ..TransferOptions::default() };
+
+        let report = agent.download_folder_with(guest.to_str().unwrap(), &local, &options).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(fs::read_to_string(local.join(" spaced/line\nbreak")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(local.join(".hidden")).unwrap(), "b");
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].0, PathBuf::from("caf\u{fffd}"));
+    }
+
+    #[test]
+    fn folder_download_refuses_paths_outside_the_destination() {
+        let listing = general_purpose::STANDARD.encode(b"f\t4\t0\t644\t0\t0\t../escape.txt\0");
+        let transport = MockTransport::new(move |command, _| match command {
+            "guest-exec" => Ok(json!({ "pid": 1 })),
+            "guest-exec-status" => Ok(json!({ "exited": true, "exitcode": 0, "out-data": listing })),
+            "guest-file-open" => panic!("nothing may be downloaded"),
+            other => Err(QgaError::CommandUnsupported { command: other.to_string() }),
+        });
+        let agent = QemuGuestAgent::with_transport("test".to_string(), Box::new(transport));
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("local");
+        let options = TransferOptions { archive: ArchiveMode::Never, ..TransferOptions::default() };
+
+        let err = agent.download_folder_with("/srv/data", &local, &options).unwrap_err();
+        assert!(format!("{:#}", err).contains("Refusing guest path"), "{:#}", err);
+        assert!(!dir.path().join("escape.txt").exists());
+    }
+
+    #[test]
+    fn guest_errors_survive_guest_file_io() {
+        let guest = Arc::new(Mutex::new(FakeGuest { refuse_writes: true, ..FakeGuest::default() }));
+        let agent = agent(&guest);
+        let dir = tempfile::tempdir().unwrap();
+        let local = local_file(&dir, &sample(5_000));
+
+        let err = agent.upload_file(&local, "/tmp/file.bin").unwrap_err();
+        assert!(matches!(QgaError::find_in(&err), Some(QgaError::Guest { .. })), "{:#}", err);
+        // A full disk is not something to skip past
+        assert!(matches!(FileOutcome::from(Err(err)), FileOutcome::Failed(_)));
+    }
+
+    #[test]
+    fn folder_transfers_skip_refused_files_only() {
+        let guest = Arc::new(Mutex::new(FakeGuest {
+            denied: vec!["/tmp/b.bin".to_string()],
+            ..FakeGuest::default()
+        }));
+        let agent = agent(&guest);
+        let dir = tempfile::tempdir().unwrap();
+        let jobs: Vec<FileJob> = ["a.bin", "b.bin", "c.bin"]
+            .iter()
+            .map(|name| {
+                let local = dir.path().join(name);
+                fs::write(&local, sample(1_000)).unwrap();
+                FileJob { local, guest: format!("/tmp/{}", name), rel: PathBuf::from(name) }
+            })
+            .collect();
+        let upload = |job: &FileJob| FileOutcome::from(agent.upload_file(&job.local, &job.guest));
+
+        let report = agent.collect_outcomes(&jobs, agent.run_jobs(&jobs, 1, upload)).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].0, PathBuf::from("b.bin"));
+        assert!(report.skipped[0].1.contains("Permission denied"));
+
+        guest.lock().unwrap().refuse_writes = true;
+        let outcomes = agent.run_jobs(&jobs, 1, upload);
+        assert!(outcomes[1..].iter().all(Option::is_none), "no file is started after a failure");
+        let err = agent.collect_outcomes(&jobs, outcomes).unwrap_err();
+        assert!(format!("{:#}", err).contains("No space left on device"));
     }
 }
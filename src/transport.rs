@@ -0,0 +1,396 @@
+use crate::error::{QgaError, QgaResult};
+use crate::session::{Channel, Session};
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::io::Read;
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Delivers a serialized QGA command to the guest agent and returns the raw JSON reply
+pub trait Transport: Send + Sync {
+    /// Fails with [`QgaError::Timeout`] if no reply arrives within `timeout`
+    fn send(&self, command: &str, timeout: Duration) -> QgaResult<String>;
+
+    /// Longest serialized command the transport can deliver, if it has a limit
+    fn max_command_len(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// How virsh gets the privileges it needs to reach the libvirt daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    /// Run virsh as the current user (e.g. members of the `libvirt` group or `qemu:///session`)
+    None,
+    Sudo,
+    Pkexec,
+}
+
+impl FromStr for Privilege {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "sudo" => Ok(Self::Sudo),
+            "pkexec" => Ok(Self::Pkexec),
+            other => anyhow::bail!("Unknown privilege mode '{}' (expected none, sudo or pkexec)", other),
+        }
+    }
+}
+
+/// Settings for reaching a domain through virsh
+#[derive(Debug, Clone)]
+pub struct VirshOptions {
+    /// libvirt connection URI passed as `--connect`, e.g. `qemu:///session` or `qemu+ssh://host/system`
+    pub connect: Option<String>,
+    pub privilege: Privilege,
+    /// virsh binary to run
+    pub virsh: PathBuf,
+}
+
+impl Default for VirshOptions {
+    fn default() -> Self {
+        Self {
+            connect: None,
+            privilege: Privilege::Sudo,
+            virsh: PathBuf::from("virsh"),
+        }
+    }
+}
+
+/// Sends each command through `virsh qemu-agent-command`
+pub struct VirshTransport {
+    pub domain: String,
+    pub options: VirshOptions,
+}
+
+impl VirshTransport {
+    pub fn new(domain: String, options: VirshOptions) -> Self {
+        Self { domain, options }
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = match self.options.privilege {
+            Privilege::None => Command::new(&self.options.virsh),
+            Privilege::Sudo => {
+                let mut cmd = Command::new("sudo");
+                cmd.arg(&self.options.virsh);
+                cmd
+            }
+            Privilege::Pkexec => {
+                let mut cmd = Command::new("pkexec");
+                cmd.arg(&self.options.virsh);
+                cmd
+            }
+        };
+
+        if let Some(uri) = &self.options.connect {
+            cmd.args(["--connect", uri]);
+        }
+
+        cmd
+    }
+}
+
+impl Transport for VirshTransport {
+    fn send(&self, command: &str, timeout: Duration) -> QgaResult<String> {
+        let name = command_name(command);
+        let agent_timeout = timeout.as_secs().max(1).to_string();
+
+        let mut child = self
+            .command()
+            .args(["qemu-agent-command", "--timeout", &agent_timeout, &self.domain, command])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| QgaError::Transport(format!("Failed to execute virsh command: {}", e)))?;
+
+        // Pipes are drained on threads so large replies cannot stall virsh while we wait
+        let (tx, rx) = mpsc::channel();
+        let stdout = child.stdout.take().map(|pipe| drain_pipe(pipe, tx.clone(), true));
+        let stderr = child.stderr.take().map(|pipe| drain_pipe(pipe, tx, false));
+
+        // virsh enforces --timeout itself; the extra grace covers sudo prompts and libvirt hiccups
+        let deadline = timeout.checked_add(VIRSH_GRACE).and_then(|t| Instant::now().checked_add(t));
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        for _ in stdout.iter().chain(stderr.iter()) {
+            let remaining = deadline.map_or(Duration::MAX, |d| d.saturating_duration_since(Instant::now()));
+            match rx.recv_timeout(remaining) {
+                Ok((true, data)) => out = data,
+                Ok((false, data)) => err = data,
+                Err(_) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(QgaError::Timeout { command: name });
+                }
+            }
+        }
+
+        let status = child
+            .wait()
+            .map_err(|e| QgaError::Transport(format!("Failed to wait for virsh: {}", e)))?;
+
+        if !status.success() {
+            return Err(QgaError::from_virsh(&name, &String::from_utf8_lossy(&err)));
+        }
+
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+
+    /// The command travels as a single argv entry, bounded by the kernel's MAX_ARG_STRLEN
+    fn max_command_len(&self) -> Option<usize> {
+        Some(128 * 1024)
+    }
+}
+
+/// Extra time granted to the virsh process beyond the agent timeout
+const VIRSH_GRACE: Duration = Duration::from_secs(5);
+
+fn drain_pipe(mut pipe: impl Read + Send + 'static, tx: mpsc::Sender<(bool, Vec<u8>)>, is_stdout: bool) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        let _ = pipe.read_to_end(&mut data);
+        let _ = tx.send((is_stdout, data));
+    })
+}
+
+/// Talks JSON directly to the guest agent chardev socket, e.g.
+/// `/var/lib/libvirt/qemu/channel/target/<domain>.org.qemu.guest_agent.0`
+pub struct UnixSocketTransport {
+    pub path: PathBuf,
+    stream: Mutex<Option<Session<UnixStream>>>,
+}
+
+impl UnixSocketTransport {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            stream: Mutex::new(None),
+        }
+    }
+}
+
+impl Transport for UnixSocketTransport {
+    fn send(&self, command: &str, timeout: Duration) -> QgaResult<String> {
+        send_over_stream(&self.stream, command, timeout, || {
+            UnixStream::connect(&self.path).with_context(|| {
+                format!("Failed to connect to guest agent socket {}", self.path.display())
+            })
+        })
+    }
+}
+
+/// Talks JSON to a guest agent chardev exposed as a TCP server (`-chardev socket,host=...,port=...`)
+pub struct TcpTransport {
+    pub address: String,
+    stream: Mutex<Option<Session<TcpStream>>>,
+}
+
+impl TcpTransport {
+    pub fn new(address: String) -> Self {
+        Self {
+            address,
+            stream: Mutex::new(None),
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, command: &str, timeout: Duration) -> QgaResult<String> {
+        send_over_stream(&self.stream, command, timeout, || {
+            TcpStream::connect(&self.address)
+                .with_context(|| format!("Failed to connect to guest agent at {}", self.address))
+        })
+    }
+}
+
+type MockHandler = dyn Fn(&str, &Value) -> QgaResult<Value> + Send + Sync;
+
+/// In-memory transport that answers commands with a closure, for exercising
+/// agent operations without a VM
+pub struct MockTransport {
+    handler: Box<MockHandler>,
+    sent: Mutex<Vec<(String, Value)>>,
+}
+
+impl MockTransport {
+    /// `handler` receives the command name and arguments and returns the `return`
+    /// payload, or the error the agent should appear to report
+    pub fn new(handler: impl Fn(&str, &Value) -> QgaResult<Value> + Send + Sync + 'static) -> Self {
+        Self {
+            handler: Box::new(handler),
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Commands received so far, in order
+    pub fn sent(&self) -> Vec<(String, Value)> {
+        self.sent.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, command: &str, _timeout: Duration) -> QgaResult<String> {
+        let request: Value = serde_json::from_str(command)?;
+        let name = request["execute"].as_str().unwrap_or_default().to_string();
+        let arguments = request.get("arguments").cloned().unwrap_or(Value::Null);
+
+        if let Ok(mut sent) = self.sent.lock() {
+            sent.push((name.clone(), arguments.clone()));
+        }
+
+        let return_value = (self.handler)(&name, &arguments)?;
+        Ok(json!({ "return": return_value }).to_string())
+    }
+}
+
+fn send_over_stream<S: Channel>(
+    slot: &Mutex<Option<Session<S>>>,
+    command: &str,
+    timeout: Duration,
+    connect: impl FnOnce() -> Result<S>,
+) -> QgaResult<String> {
+    let mut guard = slot
+        .lock()
+        .map_err(|_| QgaError::Transport("Socket transport lock poisoned".to_string()))?;
+
+    if guard.is_none() {
+        let stream = connect().map_err(|e| QgaError::Transport(format!("{:#}", e)))?;
+        *guard = Some(Session::new(stream));
+    }
+
+    let session = guard.as_mut().expect("session connected above");
+    let result = session.execute(command, timeout);
+
+    // A timed out session resyncs on the next command; anything else (the socket
+    // went away, the agent restarted) needs a fresh connection
+    if let Err(QgaError::Transport(_)) = &result {
+        *guard = None;
+    }
+
+    result
+}
+
+/// Extracts the `execute` name from a serialized command, for error reporting
+fn command_name(command: &str) -> String {
+    serde_json::from_str::<Value>(command)
+        .ok()
+        .and_then(|v| v["execute"].as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+
+    /// One connection of a fake guest agent, answering requests by hand
+    struct FakeAgent<S: Read + Write> {
+        stream: BufReader<S>,
+    }
+
+    impl<S: Read + Write> FakeAgent<S> {
+        fn new(stream: S) -> Self {
+            Self { stream: BufReader::new(stream) }
+        }
+
+        /// The next request as sent, sync sentinel included
+        fn raw_request(&mut self) -> Vec<u8> {
+            let mut line = Vec::new();
+            self.stream.read_until(b'\n', &mut line).unwrap();
+            line
+        }
+
+        fn request(&mut self, execute: &str) -> Value {
+            let request: Value = serde_json::from_slice(&self.raw_request()).unwrap();
+            assert_eq!(request["execute"], execute);
+            request
+        }
+
+        /// Answers the handshake after output left over from an earlier exchange
+        fn answer_sync(&mut self) {
+            let raw = self.raw_request();
+            assert_eq!(raw[0], 0xFF, "the handshake resets the agent's parser first");
+            let request: Value = serde_json::from_slice(&raw[1..]).unwrap();
+            assert_eq!(request["execute"], "guest-sync-delimited");
+
+            let writer = self.stream.get_mut();
+            writer.write_all(b"{\"return\": {}, \"id\": 7}\n").unwrap();
+            writer.write_all(&[0xFF]).unwrap();
+            writeln!(writer, "{{\"return\": {}}}", request["arguments"]["id"]).unwrap();
+        }
+
+        fn reply(&mut self, request: &Value, value: Value) {
+            writeln!(self.stream.get_mut(), "{}", json!({ "return": value, "id": request["id"] })).unwrap();
+        }
+    }
+
+    #[test]
+    fn unix_socket_syncs_once_and_skips_stale_replies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("qga.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let agent = thread::spawn(move || {
+            let mut agent = FakeAgent::new(listener.accept().unwrap().0);
+            agent.answer_sync();
+            let ping = agent.request("guest-ping");
+            let stale = json!({ "id": ping["id"].as_u64().unwrap() ^ 1 });
+            agent.reply(&stale, json!("stale"));
+            agent.reply(&ping, json!("pong"));
+
+            // The connection stays synced for later commands
+            let info = agent.request("guest-info");
+            agent.reply(&info, json!({ "version": "9.0" }));
+        });
+
+        let transport = UnixSocketTransport::new(path);
+        let timeout = Duration::from_secs(5);
+        let reply: Value = serde_json::from_str(&transport.send(r#"{"execute": "guest-ping"}"#, timeout).unwrap()).unwrap();
+        assert_eq!(reply["return"], "pong");
+        let reply: Value = serde_json::from_str(&transport.send(r#"{"execute": "guest-info"}"#, timeout).unwrap()).unwrap();
+        assert_eq!(reply["return"]["version"], "9.0");
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn tcp_resyncs_after_a_timeout_and_reconnects_after_a_drop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let transport = TcpTransport::new(listener.local_addr().unwrap().to_string());
+        let agent = thread::spawn(move || {
+            let mut agent = FakeAgent::new(listener.accept().unwrap().0);
+            agent.answer_sync();
+            // Never answered, so its reply is not waiting on the stream either
+            agent.request("guest-info");
+            agent.answer_sync();
+            let ping = agent.request("guest-ping");
+            agent.reply(&ping, json!({}));
+            drop(agent);
+
+            let mut agent = FakeAgent::new(listener.accept().unwrap().0);
+            agent.answer_sync();
+            let ping = agent.request("guest-ping");
+            agent.reply(&ping, json!({}));
+        });
+
+        let err = transport.send(r#"{"execute": "guest-info"}"#, Duration::from_millis(200)).unwrap_err();
+        assert!(matches!(err, QgaError::Timeout { ref command } if command == "guest-info"), "{:?}", err);
+        let timeout = Duration::from_secs(5);
+        transport.send(r#"{"execute": "guest-ping"}"#, timeout).unwrap();
+
+        let err = transport.send(r#"{"execute": "guest-ping"}"#, timeout).unwrap_err();
+        assert!(matches!(err, QgaError::Transport(_)), "{:?}", err);
+        transport.send(r#"{"execute": "guest-ping"}"#, timeout).unwrap();
+        agent.join().unwrap();
+    }
+}